@@ -0,0 +1,680 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Admin API under `/admin`, for keys with the `admin` permission.
+//!
+//! API keys, personas and upstream models created, replaced or deleted here
+//! are stored as [`Overrides`] in the app database and applied over the
+//! config files, at startup, on reload and right after every change. Only
+//! the SHA-256 of the keys is stored.
+
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use chrono::Utc;
+use pickledb::PickleDb;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use crate::{
+    appctx::AppContext,
+    auth,
+    config::{ApiKey, Config, KeyPolicy, KeyRestrictions, LanguagePolicy, Persona, UpstreamModel},
+    endpoint,
+    error::ApiError,
+    llm::OpenAiBackend,
+    secret::{self, Secret},
+};
+
+type OAIAppContext = AppContext<OpenAiBackend>;
+
+const OVERRIDES_KEY: &str = "admin:overrides";
+
+/// Config entries addressed by name in the admin API.
+trait Named {
+    fn name(&self) -> &str;
+}
+
+impl Named for ApiKey {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Named for Persona {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Named for UpstreamModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Entries of one config list created or replaced through the admin API,
+/// and the names of the ones it deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: serde::de::DeserializeOwned"))]
+struct Entries<T> {
+    #[serde(default)]
+    upserted: Vec<T>,
+    #[serde(default)]
+    deleted: Vec<String>,
+}
+
+impl<T> Default for Entries<T> {
+    fn default() -> Self {
+        Entries {
+            upserted: vec![],
+            deleted: vec![],
+        }
+    }
+}
+
+impl<T: Named + Clone> Entries<T> {
+    fn upsert(&mut self, entry: T) {
+        self.deleted.retain(|name| name != entry.name());
+        self.upserted.retain(|e| e.name() != entry.name());
+        self.upserted.push(entry);
+    }
+
+    fn delete(&mut self, name: &str) {
+        self.upserted.retain(|e| e.name() != name);
+        if !self.deleted.iter().any(|n| n == name) {
+            self.deleted.push(name.to_string());
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.deleted.is_empty()
+    }
+
+    fn manages(&self, name: &str) -> bool {
+        self.upserted.iter().any(|e| e.name() == name)
+    }
+
+    /// Replaces entries in place, appends the new ones.
+    fn apply(&self, list: &mut Vec<T>) {
+        list.retain(|e| !self.deleted.iter().any(|name| name == e.name()));
+        for entry in &self.upserted {
+            match list.iter_mut().find(|e| e.name() == entry.name()) {
+                Some(e) => *e = entry.clone(),
+                None => list.push(entry.clone()),
+            }
+        }
+    }
+}
+
+/// Changes made through the admin API.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Overrides {
+    #[serde(default)]
+    api_keys: Entries<ApiKey>,
+    #[serde(default)]
+    personas: Entries<Persona>,
+    #[serde(default)]
+    models: Entries<UpstreamModel>,
+}
+
+impl Overrides {
+    pub fn load(db: &PickleDb) -> Self {
+        db.get(OVERRIDES_KEY).unwrap_or_default()
+    }
+
+    pub fn save(&self, db: &mut PickleDb) -> Result<(), ApiError> {
+        let mut overrides = self.clone();
+        for key in overrides.api_keys.upserted.iter_mut() {
+            key.key = key.key.hashed();
+        }
+        db.set(OVERRIDES_KEY, &overrides)
+            .map_err(|e| ApiError::Internal(format!("Cannot store the change: {}", e)))
+    }
+
+    /// Replaces the keys stored in plain text, as they were before only
+    /// their hashes were, with their hashes.
+    pub fn migrate(db: &mut PickleDb) {
+        let overrides = Overrides::load(db);
+        if overrides
+            .api_keys
+            .upserted
+            .iter()
+            .all(|key| key.key.is_hashed())
+        {
+            return;
+        }
+        if let Err(e) = overrides.save(db) {
+            error!("API keys of the admin API kept in plain text: {}", e);
+        }
+    }
+
+    /// `config` with the changes applied, if it is still valid.
+    pub fn apply(&self, config: &Config) -> Result<Config, String> {
+        let mut config = config.clone();
+        self.api_keys.apply(&mut config.api_keys);
+        if !self.personas.is_empty() {
+            // keep the built-in personas when the files define none
+            config.personas = config.personas().to_vec();
+            self.personas.apply(&mut config.personas);
+        }
+        self.models.apply(&mut config.models);
+
+        let issues = config.validate();
+        if issues.is_empty() {
+            return Ok(config);
+        }
+        Err(issues
+            .iter()
+            .map(|issue| match &issue.path {
+                Some(path) => format!("`{}`: {}", path, issue),
+                None => issue.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; "))
+    }
+}
+
+fn list<T: serde::Serialize>(data: Vec<T>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "object": "list",
+        "data": data,
+    }))
+}
+
+fn not_found(kind: &str, name: &str) -> ApiError {
+    ApiError::NotFound(format!("No {} found with name '{}'", kind, name))
+}
+
+fn already_exists(kind: &str, name: &str) -> ApiError {
+    ApiError::BadRequest(format!(
+        "Name '{}' is already used by another {}",
+        name, kind
+    ))
+}
+
+/// An API key as listed by the admin API, without the key itself.
+#[derive(Debug, Serialize)]
+struct ApiKeyInfo {
+    name: String,
+    description: Option<String>,
+    permissions: Vec<String>,
+    language: Option<LanguagePolicy>,
+    client_cert: Option<String>,
+    projects: Vec<String>,
+    #[serde(flatten)]
+    policy: KeyPolicy,
+    #[serde(flatten)]
+    restrictions: KeyRestrictions,
+    /// Created or changed through the admin API, rather than in the files.
+    managed: bool,
+}
+
+impl ApiKeyInfo {
+    fn new(key: &ApiKey, overrides: &Overrides) -> Self {
+        ApiKeyInfo {
+            name: key.name.clone(),
+            description: key.description.clone(),
+            permissions: key.permissions.clone(),
+            language: key.language.clone(),
+            client_cert: key.client_cert.clone(),
+            projects: key.projects.clone(),
+            policy: key.policy.clone(),
+            restrictions: key.restrictions.clone(),
+            managed: overrides.api_keys.manages(&key.name),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    pub language: Option<LanguagePolicy>,
+    pub client_cert: Option<String>,
+    #[serde(default)]
+    pub projects: Vec<String>,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+    #[serde(flatten)]
+    pub restrictions: KeyRestrictions,
+}
+
+/// Fields left out are kept.
+#[derive(Deserialize, Debug)]
+pub struct UpdateApiKeyRequest {
+    pub description: Option<String>,
+    pub permissions: Option<Vec<String>>,
+    pub language: Option<LanguagePolicy>,
+    pub client_cert: Option<String>,
+    pub projects: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+    #[serde(flatten)]
+    pub restrictions: KeyRestrictions,
+    /// Replaces the key with a new one.
+    #[serde(default)]
+    pub rotate: bool,
+}
+
+fn overrides(ctx: &OAIAppContext) -> Overrides {
+    Overrides::load(&ctx.db.lock().unwrap())
+}
+
+#[get("/api_keys")]
+pub async fn list_api_keys(ctx: web::Data<OAIAppContext>) -> impl Responder {
+    let overrides = overrides(&ctx);
+    list(
+        ctx.config()
+            .api_keys
+            .iter()
+            .map(|key| ApiKeyInfo::new(key, &overrides))
+            .collect(),
+    )
+}
+
+#[get("/api_keys/{name}")]
+pub async fn get_api_key(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let config = ctx.config();
+    let key = config
+        .api_keys
+        .iter()
+        .find(|key| key.name == *path)
+        .ok_or_else(|| not_found("API key", &path))?;
+    Ok(HttpResponse::Ok().json(ApiKeyInfo::new(key, &overrides(&ctx))))
+}
+
+/// Creates a key, the response is the only time the key is shown.
+#[post("/api_keys")]
+pub async fn create_api_key(
+    data: web::Json<CreateApiKeyRequest>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let data = data.into_inner();
+    if ctx
+        .config()
+        .api_keys
+        .iter()
+        .any(|key| key.name == data.name)
+    {
+        return Err(already_exists("API key", &data.name));
+    }
+
+    let token = secret::generate_key();
+    let key = ApiKey {
+        key: Secret::new(token.clone()),
+        name: data.name,
+        description: data.description,
+        permissions: data.permissions,
+        language: data.language,
+        client_cert: data.client_cert,
+        projects: data.projects,
+        policy: data.policy,
+        restrictions: data.restrictions,
+    };
+    let name = key.name.clone();
+    ctx.update_overrides(|overrides| {
+        overrides.api_keys.upsert(key);
+        Ok(())
+    })?;
+    info!("admin: API key `{}` created", name);
+
+    let config = ctx.config();
+    let key = config.api_keys.iter().find(|key| key.name == name);
+    Ok(HttpResponse::Ok().json(json!({
+        "key": token,
+        "api_key": key.map(|key| ApiKeyInfo::new(key, &overrides(&ctx))),
+    })))
+}
+
+#[post("/api_keys/{name}")]
+pub async fn update_api_key(
+    path: web::Path<String>,
+    data: web::Json<UpdateApiKeyRequest>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let data = data.into_inner();
+    let mut key = ctx
+        .config()
+        .api_keys
+        .iter()
+        .find(|key| key.name == *path)
+        .cloned()
+        .ok_or_else(|| not_found("API key", &path))?;
+
+    if let Some(description) = data.description {
+        key.description = Some(description);
+    }
+    if let Some(permissions) = data.permissions {
+        key.permissions = permissions;
+    }
+    if let Some(language) = data.language {
+        key.language = Some(language);
+    }
+    if let Some(client_cert) = data.client_cert {
+        key.client_cert = Some(client_cert);
+    }
+    if let Some(projects) = data.projects {
+        key.projects = projects;
+    }
+    key.policy = data.policy.inherit(&key.policy);
+    key.restrictions = data.restrictions.inherit(&key.restrictions);
+    let token = data.rotate.then(secret::generate_key);
+    if let Some(token) = &token {
+        key.key = Secret::new(token.clone());
+    }
+
+    ctx.update_overrides(|overrides| {
+        overrides.api_keys.upsert(key);
+        Ok(())
+    })?;
+    info!("admin: API key `{}` updated", path);
+
+    let config = ctx.config();
+    let key = config.api_keys.iter().find(|key| key.name == *path);
+    Ok(HttpResponse::Ok().json(json!({
+        "key": token,
+        "api_key": key.map(|key| ApiKeyInfo::new(key, &overrides(&ctx))),
+    })))
+}
+
+#[delete("/api_keys/{name}")]
+pub async fn delete_api_key(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    if !ctx.config().api_keys.iter().any(|key| key.name == *path) {
+        return Err(not_found("API key", &path));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.api_keys.delete(&path);
+        Ok(())
+    })?;
+    info!("admin: API key `{}` deleted", path);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "name": *path,
+        "object": "api_key.deleted",
+        "deleted": true,
+    })))
+}
+
+#[get("/personas")]
+pub async fn list_personas(ctx: web::Data<OAIAppContext>) -> impl Responder {
+    list(ctx.config().personas().to_vec())
+}
+
+#[get("/personas/{name}")]
+pub async fn get_persona(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let config = ctx.config();
+    let persona = config
+        .persona(&path)
+        .ok_or_else(|| not_found("persona", &path))?;
+    Ok(HttpResponse::Ok().json(persona))
+}
+
+#[post("/personas")]
+pub async fn create_persona(
+    data: web::Json<Persona>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let persona = data.into_inner();
+    if ctx.config().persona(&persona.name).is_some() {
+        return Err(already_exists("persona", &persona.name));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.personas.upsert(persona.clone());
+        Ok(())
+    })?;
+    info!("admin: persona `{}` created", persona.name);
+    Ok(HttpResponse::Ok().json(persona))
+}
+
+/// Replaces a persona, the body is the complete persona.
+#[post("/personas/{name}")]
+pub async fn update_persona(
+    path: web::Path<String>,
+    data: web::Json<Persona>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let persona = data.into_inner();
+    if persona.name != *path {
+        return Err(ApiError::BadRequest("Personas cannot be renamed".into()));
+    }
+    if ctx.config().persona(&path).is_none() {
+        return Err(not_found("persona", &path));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.personas.upsert(persona.clone());
+        Ok(())
+    })?;
+    info!("admin: persona `{}` updated", persona.name);
+    Ok(HttpResponse::Ok().json(persona))
+}
+
+#[delete("/personas/{name}")]
+pub async fn delete_persona(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    if ctx.config().persona(&path).is_none() {
+        return Err(not_found("persona", &path));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.personas.delete(&path);
+        Ok(())
+    })?;
+    info!("admin: persona `{}` deleted", path);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "name": *path,
+        "object": "persona.deleted",
+        "deleted": true,
+    })))
+}
+
+#[get("/models")]
+pub async fn list_models(ctx: web::Data<OAIAppContext>) -> impl Responder {
+    list(ctx.config().models.clone())
+}
+
+#[get("/models/{name}")]
+pub async fn get_model(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let config = ctx.config();
+    let model = config
+        .upstream_model(&path)
+        .ok_or_else(|| not_found("model", &path))?;
+    Ok(HttpResponse::Ok().json(model))
+}
+
+#[post("/models")]
+pub async fn create_model(
+    data: web::Json<UpstreamModel>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let model = data.into_inner();
+    if ctx.config().upstream_model(&model.name).is_some() {
+        return Err(already_exists("model", &model.name));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.models.upsert(model.clone());
+        Ok(())
+    })?;
+    info!("admin: model `{}` created", model.name);
+    Ok(HttpResponse::Ok().json(model))
+}
+
+/// Replaces an upstream model, the body is the complete model.
+#[post("/models/{name}")]
+pub async fn update_model(
+    path: web::Path<String>,
+    data: web::Json<UpstreamModel>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    let model = data.into_inner();
+    if model.name != *path {
+        return Err(ApiError::BadRequest("Models cannot be renamed".into()));
+    }
+    if ctx.config().upstream_model(&path).is_none() {
+        return Err(not_found("model", &path));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.models.upsert(model.clone());
+        Ok(())
+    })?;
+    info!("admin: model `{}` updated", model.name);
+    Ok(HttpResponse::Ok().json(model))
+}
+
+#[delete("/models/{name}")]
+pub async fn delete_model(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+) -> Result<impl Responder, ApiError> {
+    if ctx.config().upstream_model(&path).is_none() {
+        return Err(not_found("model", &path));
+    }
+    ctx.update_overrides(|overrides| {
+        overrides.models.delete(&path);
+        Ok(())
+    })?;
+    info!("admin: model `{}` deleted", path);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "name": *path,
+        "object": "model.deleted",
+        "deleted": true,
+    })))
+}
+
+#[derive(Debug, Serialize)]
+struct UsageEntry {
+    endpoint: String,
+    /// Keys deleted since are reported as `null`.
+    key_name: Option<String>,
+    requests: u32,
+}
+
+/// Request counts per endpoint and API key.
+#[get("/usage")]
+pub async fn usage(ctx: web::Data<OAIAppContext>) -> impl Responder {
+    let config = ctx.config();
+    // counters of deleted keys under one entry
+    let mut usage: BTreeMap<(String, Option<String>), u32> = BTreeMap::new();
+    for (endpoint, token, hits) in endpoint::metric_counters(&ctx) {
+        let key_name = auth::caller_name(&config, &token);
+        *usage.entry((endpoint, key_name)).or_default() += hits;
+    }
+
+    list(
+        usage
+            .into_iter()
+            .map(|((endpoint, key_name), requests)| UsageEntry {
+                endpoint,
+                key_name,
+                requests,
+            })
+            .collect(),
+    )
+}
+
+/// Streams being relayed right now.
+#[get("/streams")]
+pub async fn streams(ctx: web::Data<OAIAppContext>) -> impl Responder {
+    let now = Utc::now();
+    list(
+        ctx.streams
+            .list()
+            .into_iter()
+            .map(|stream| {
+                let elapsed_ms = (now - stream.started_at).num_milliseconds();
+                json!({
+                    "id": stream.id,
+                    "endpoint": stream.endpoint,
+                    "key_name": stream.key_name,
+                    "persona": stream.persona,
+                    "started_at": stream.started_at,
+                    "elapsed_ms": elapsed_ms,
+                })
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db(name: &str) -> PickleDb {
+        let path = std::env::temp_dir().join(format!("restoai-admin-{}.db", name));
+        PickleDb::new_json(path, pickledb::PickleDbDumpPolicy::AutoDump)
+    }
+
+    fn key(name: &str, token: &str) -> ApiKey {
+        ApiKey {
+            key: Secret::new(token),
+            name: name.into(),
+            description: None,
+            permissions: vec!["openai:api".into()],
+            language: None,
+            client_cert: None,
+            projects: vec![],
+            policy: Default::default(),
+            restrictions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn keys_are_stored_hashed() {
+        let mut db = db("hashed");
+        let mut overrides = Overrides::default();
+        overrides
+            .api_keys
+            .upsert(key("Admin made", "nsk-abcdefgh12345678"));
+        overrides.save(&mut db).unwrap();
+
+        let stored = serde_json::to_string(&db.get::<serde_json::Value>(OVERRIDES_KEY)).unwrap();
+        assert!(!stored.contains("nsk-abcdefgh12345678"));
+        let loaded = Overrides::load(&db);
+        assert!(loaded.api_keys.upserted[0].key.is_hashed());
+        assert!(loaded.api_keys.upserted[0]
+            .key
+            .matches("nsk-abcdefgh12345678"));
+    }
+
+    #[test]
+    fn plain_keys_are_hashed_at_startup() {
+        let mut db = db("migrate");
+        let mut overrides = Overrides::default();
+        overrides
+            .api_keys
+            .upsert(key("Old", "nsk-oldplaintext0001"));
+        overrides.api_keys.delete("Gone");
+        db.set(OVERRIDES_KEY, &overrides).unwrap();
+
+        Overrides::migrate(&mut db);
+        let loaded = Overrides::load(&db);
+        let key = &loaded.api_keys.upserted[0];
+        assert!(key.key.is_hashed());
+        assert!(key.key.matches("nsk-oldplaintext0001"));
+        assert_eq!(loaded.api_keys.deleted, ["Gone"]);
+    }
+}
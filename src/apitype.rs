@@ -64,18 +64,6 @@ impl From<openai_dive::v1::resources::shared::Usage> for ChatCompletionUsage {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct ChatCompletion {
-    pub id: String,
-    pub choices: Vec<ChatCompletionChoice>,
-
-    pub created: u32,
-    //pub model: String,
-    //pub system_fingerprint: String,
-    pub object: String,
-    pub usage: Option<ChatCompletionUsage>,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatCompletionResponse {
     /// A unique identifier for the chat completion.
@@ -220,12 +208,12 @@ impl From<openai_dive::v1::resources::chat::ImageUrl> for ImageUrl {
     }
 }
 
-impl Into<openai_dive::v1::resources::chat::ImageUrl> for ImageUrl {
-    fn into(self) -> openai_dive::v1::resources::chat::ImageUrl {
+impl From<ImageUrl> for openai_dive::v1::resources::chat::ImageUrl {
+    fn from(url: ImageUrl) -> openai_dive::v1::resources::chat::ImageUrl {
         openai_dive::v1::resources::chat::ImageUrl {
-            r#type: self.r#type,
-            text: self.text,
-            image_url: self.image_url.into(),
+            r#type: url.r#type,
+            text: url.text,
+            image_url: url.image_url.into(),
         }
     }
 }
@@ -272,9 +260,9 @@ impl From<openai_dive::v1::resources::chat::ChatMessageContent> for ChatMessageC
     }
 }
 
-impl Into<openai_dive::v1::resources::chat::ChatMessageContent> for ChatMessageContent {
-    fn into(self) -> openai_dive::v1::resources::chat::ChatMessageContent {
-        match self {
+impl From<ChatMessageContent> for openai_dive::v1::resources::chat::ChatMessageContent {
+    fn from(content: ChatMessageContent) -> openai_dive::v1::resources::chat::ChatMessageContent {
+        match content {
             ChatMessageContent::Text(text) => {
                 openai_dive::v1::resources::chat::ChatMessageContent::Text(text)
             }
@@ -361,12 +349,20 @@ impl From<openai_dive::v1::resources::chat::ChatCompletionChoice> for ChatComple
     fn from(choice: openai_dive::v1::resources::chat::ChatCompletionChoice) -> Self {
         Self {
             message: choice.message.into(),
-            finish_reason: serde_json::to_string(&choice.finish_reason).ok(),
+            finish_reason: choice.finish_reason.as_ref().map(finish_reason_name),
             index: choice.index,
         }
     }
 }
 
+/// The API name of a finish reason, e.g. `stop` or `content_filter`.
+pub fn finish_reason_name(reason: &FinishReason) -> String {
+    serde_json::to_value(reason)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DeltaChatMessage {
     /// The role of the author of this message.
@@ -490,6 +486,45 @@ pub struct ChatCompletionParameters {
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect abuse.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+
+    /// Variables for the persona system prompt template.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageTokens {
+    pub role: Role,
+    /// Tokens used by the message, including the chat format overhead.
+    pub count: u32,
+    /// Token ids of the message content, omitted for system messages, which
+    /// decode to the persona prompt, and when the upstream model has no
+    /// tokenizer configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<u32>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenizeResponse {
+    /// The object type, which is always "tokenize".
+    pub object: String,
+    pub model: String,
+    /// Name of the tokenizer used, "estimate" when counts are approximated.
+    pub tokenizer: String,
+    /// Tokens per message, starting with the persona system prompt.
+    pub messages: Vec<MessageTokens>,
+    pub prompt_tokens: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenCount {
+    /// The object type, which is always "token_count".
+    pub object: String,
+    pub model: String,
+    /// Tokens of the assembled prompt, including the persona system prompt.
+    pub prompt_tokens: u32,
+    /// Context window of the upstream model.
+    pub context_window: u32,
 }
 
 use tokio::sync::mpsc;
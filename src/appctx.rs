@@ -1,42 +1,128 @@
+use arc_swap::ArcSwap;
 use std::sync::{Arc, Mutex};
 
 use pickledb::PickleDb;
 
-use crate::{config::Config, llm::LlmBackend};
+use crate::{
+    admin::Overrides, audit::AuditLog, config::Config, endpoint, error::ApiError, jwt::JwksCache,
+    llm::LlmBackend, streamer::StreamRegistry, tenant::RateLimiter, thread, usage,
+};
+
+/// The app database, dumped in full on every change.
+pub const DB_PATH: &str = "restoai.db";
 
 pub struct AppContext<T>
 where
     T: LlmBackend,
 {
-    pub llm_backend: Arc<T>,
-    pub config: Config,
+    /// Swapped on reload, requests in flight keep the backend they started
+    /// with.
+    llm_backend: ArcSwap<T>,
+    /// Config of the files, before the admin overrides.
+    base_config: ArcSwap<Config>,
+    config: ArcSwap<Config>,
     pub db: Arc<Mutex<PickleDb>>,
+    pub audit: Arc<AuditLog>,
+    pub streams: Arc<StreamRegistry>,
+    pub limiter: RateLimiter,
+    pub jwks: JwksCache,
+    /// Why the last SIGHUP reload failed, until one succeeds.
+    reload_error: parking_lot::Mutex<Option<String>>,
 }
 
 impl<T> AppContext<T>
 where
     T: LlmBackend,
 {
-    pub fn new(llm_backend: Arc<T>, config: Config) -> Arc<Self> {
-        let path = "restoai.db";
+    pub fn new(config: Config, audit: Arc<AuditLog>) -> Result<Arc<Self>, String> {
+        let path = DB_PATH;
 
         // check if db exists
-        let db = if !std::path::Path::new(path).exists() {
+        let mut db = if !std::path::Path::new(path).exists() {
             PickleDb::new_json(path, pickledb::PickleDbDumpPolicy::AutoDump)
         } else {
             PickleDb::load_json(path, pickledb::PickleDbDumpPolicy::AutoDump)
                 .expect("Failed to load db")
         };
 
+        let effective = Overrides::load(&db).apply(&config).unwrap_or_else(|e| {
+            error!("admin changes not applied: {}", e);
+            config.clone()
+        });
+        // callers used to be known by their token
+        thread::migrate_owners(&mut db, &effective);
+        endpoint::migrate_counters(&mut db, &effective);
+        usage::migrate(&mut db, &effective);
+        // the keys themselves, last as the migrations above look them up
+        Overrides::migrate(&mut db);
+
+        let llm_backend = T::from_config(&effective)?;
         let db = Arc::new(Mutex::new(db));
-        Arc::new(Self {
-            llm_backend,
-            config,
+        Ok(Arc::new(Self {
+            llm_backend: ArcSwap::new(llm_backend),
+            base_config: ArcSwap::from_pointee(config),
+            config: ArcSwap::from_pointee(effective),
             db,
-        })
+            audit,
+            streams: Arc::new(StreamRegistry::default()),
+            limiter: RateLimiter::default(),
+            jwks: JwksCache::default(),
+            reload_error: Default::default(),
+        }))
+    }
+
+    pub fn from_config(config: &Config, audit: Arc<AuditLog>) -> Result<Arc<Self>, String> {
+        Self::new(config.clone(), audit)
+    }
+
+    pub fn llm_backend(&self) -> Arc<T> {
+        self.llm_backend.load_full()
+    }
+
+    /// Effective config, admin overrides applied.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    /// Replaces the config of the files, applies the admin overrides and
+    /// rebuilds the backend. The current ones stay in place when the result
+    /// is invalid or the backend cannot be created.
+    pub fn reload(&self, config: Config) -> Result<(), String> {
+        let effective = Overrides::load(&self.db.lock().unwrap()).apply(&config)?;
+        self.swap(config, effective)
+    }
+
+    pub fn reload_error(&self) -> Option<String> {
+        self.reload_error.lock().clone()
+    }
+
+    pub fn set_reload_error(&self, error: Option<String>) {
+        *self.reload_error.lock() = error;
+    }
+
+    /// Changes the admin overrides, then stores and applies them when the
+    /// resulting config is valid.
+    pub fn update_overrides<F>(&self, change: F) -> Result<(), ApiError>
+    where
+        F: FnOnce(&mut Overrides) -> Result<(), ApiError>,
+    {
+        // held throughout, so concurrent changes apply one after the other
+        let mut db = self.db.lock().unwrap();
+        let mut overrides = Overrides::load(&db);
+        change(&mut overrides)?;
+
+        let base = self.base_config.load_full();
+        let effective = overrides.apply(&base).map_err(ApiError::BadRequest)?;
+        overrides.save(&mut db)?;
+        self.swap((*base).clone(), effective)
+            .map_err(ApiError::Internal)
     }
 
-    pub fn from_config(config: &Config) -> Arc<Self> {
-        Self::new(T::from_config(config), config.clone())
+    fn swap(&self, base: Config, effective: Config) -> Result<(), String> {
+        let llm_backend = T::from_config(&effective)?;
+        self.llm_backend.store(llm_backend);
+        self.base_config.store(Arc::new(base));
+        self.config.store(Arc::new(effective));
+        Ok(())
     }
 }
@@ -0,0 +1,465 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Audit log.
+//!
+//! One JSON object per completion request, appended to
+//! `<dir>/audit-YYYY-MM-DD.jsonl`. A new file is started every day (UTC) and
+//! whenever the current one exceeds `max_file_size`, as
+//! `audit-YYYY-MM-DD.1.jsonl` and so on. Files older than `retention_days`
+//! are deleted on rotation.
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use openai_dive::v1::resources::chat::ChatMessage;
+use parking_lot::Mutex;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::Instant,
+};
+
+use crate::{
+    apitype::{self, ChatCompletionUsage},
+    config::AuditConfig,
+    error::ApiError,
+    llm::{Prompt, PromptContext},
+    streamer::Relayed,
+    tokenizer::Tokenizer,
+};
+
+/// A completion request as recorded in the audit log. The API key is only
+/// identified by its name.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    #[serde(skip)]
+    started: Instant,
+    #[serde(skip)]
+    bodies: bool,
+    /// Tokens of the prompt sent upstream, for streams without usage.
+    #[serde(skip)]
+    prompt_tokens: u32,
+
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub latency_ms: u64,
+    pub endpoint: String,
+    pub request_id: Option<String>,
+    pub key_name: Option<String>,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub user: Option<String>,
+    pub persona: String,
+    pub upstream_model: Option<String>,
+    pub stream: bool,
+    pub finish_reason: Option<String>,
+    /// Reported by upstream, counted locally for streams.
+    pub usage: Option<ChatCompletionUsage>,
+    /// USD, from the prices of the upstream model.
+    pub cost: Option<f64>,
+    pub error: Option<String>,
+    /// Messages sent upstream, after redaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<Vec<ChatMessage>>,
+    /// Answer returned to the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+}
+
+impl AuditEntry {
+    /// Records the prompt about to be submitted.
+    pub fn prompt(&mut self, prompt: &Prompt, tokenizer: &Tokenizer) {
+        self.upstream_model = Some(prompt.parameters.model.clone());
+        self.prompt_tokens = tokenizer.count_messages(&prompt.parameters.messages);
+        if self.bodies {
+            self.prompt = Some(prompt.parameters.messages.clone());
+        }
+    }
+
+    pub fn response(&mut self, response: &apitype::ChatCompletionResponse) {
+        let choice = response.choices.first();
+        self.finish_reason = choice.and_then(|c| c.finish_reason.clone());
+        self.usage = response.usage.clone();
+        if self.bodies {
+            self.response = choice.and_then(|c| match &c.message.content {
+                apitype::ChatMessageContent::Text(text) => Some(text.clone()),
+                _ => None,
+            });
+        }
+        self.finish();
+    }
+
+    pub fn stream(&mut self, relayed: &Relayed, tokenizer: &Tokenizer) {
+        self.finish_reason = relayed
+            .finish_reason
+            .as_ref()
+            .map(apitype::finish_reason_name);
+        let completion_tokens = tokenizer.count(&relayed.content);
+        self.usage = Some(ChatCompletionUsage {
+            completion_tokens: Some(completion_tokens),
+            prompt_tokens: self.prompt_tokens,
+            total_tokens: self.prompt_tokens + completion_tokens,
+        });
+        if self.bodies {
+            self.response = Some(relayed.content.clone());
+        }
+        self.finish();
+    }
+
+    pub fn error(&mut self, error: &ApiError) {
+        self.error = Some(error.to_string());
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        self.finished_at = Some(Utc::now());
+        self.latency_ms = self.started.elapsed().as_millis() as u64;
+    }
+}
+
+struct AuditFile {
+    date: NaiveDate,
+    index: u32,
+    size: u64,
+    file: File,
+}
+
+pub struct AuditLog {
+    config: AuditConfig,
+    current: Mutex<Option<AuditFile>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Result<Self, String> {
+        if config.enabled {
+            fs::create_dir_all(config.dir())
+                .map_err(|e| format!("Cannot create audit dir `{}`: {}", config.dir(), e))?;
+        }
+        Ok(Self {
+            config: config.clone(),
+            current: Mutex::new(None),
+        })
+    }
+
+    /// Starts the entry of a request to `endpoint`.
+    pub fn start(
+        &self,
+        endpoint: &str,
+        prompt_ctx: &PromptContext,
+        persona: &str,
+        stream: bool,
+    ) -> AuditEntry {
+        AuditEntry {
+            started: Instant::now(),
+            bodies: prompt_ctx
+                .tenant
+                .policy
+                .audit_bodies
+                .unwrap_or(self.config.bodies()),
+            prompt_tokens: 0,
+            started_at: Utc::now(),
+            finished_at: None,
+            latency_ms: 0,
+            endpoint: endpoint.to_string(),
+            request_id: prompt_ctx.request_id.clone(),
+            key_name: prompt_ctx.key_name.clone(),
+            organization: prompt_ctx.tenant.organization.clone(),
+            project: prompt_ctx.tenant.project.clone(),
+            user: prompt_ctx.user.clone(),
+            persona: persona.to_string(),
+            upstream_model: None,
+            stream,
+            finish_reason: None,
+            usage: None,
+            cost: None,
+            error: None,
+            prompt: None,
+            response: None,
+        }
+    }
+
+    /// Appends `entry`. Failures are logged, they never fail the request.
+    pub fn write(&self, entry: &AuditEntry) {
+        self.write_on(entry, Utc::now().date_naive())
+    }
+
+    /// Appends `entry` to the files of `today`.
+    fn write_on(&self, entry: &AuditEntry, today: NaiveDate) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("cannot serialize audit entry: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut current = self.current.lock();
+        let rotate = match &*current {
+            Some(f) => f.date != today || f.size + line.len() as u64 > self.config.max_file_size(),
+            None => true,
+        };
+        if rotate {
+            let index = match &*current {
+                Some(f) if f.date == today => f.index + 1,
+                _ => 0,
+            };
+            *current = self.open(today, index);
+            self.remove_expired(today);
+        }
+
+        if let Some(f) = current.as_mut() {
+            match f.file.write_all(line.as_bytes()) {
+                Ok(()) => f.size += line.len() as u64,
+                Err(e) => error!("cannot write audit log: {}", e),
+            }
+        }
+    }
+
+    fn path(&self, date: NaiveDate, index: u32) -> PathBuf {
+        let name = match index {
+            0 => format!("audit-{}.jsonl", date),
+            n => format!("audit-{}.{}.jsonl", date, n),
+        };
+        PathBuf::from(self.config.dir()).join(name)
+    }
+
+    /// Opens the first file of `date` from `index` on with room left, files
+    /// written before a restart are appended to.
+    fn open(&self, date: NaiveDate, mut index: u32) -> Option<AuditFile> {
+        let max_size = self.config.max_file_size();
+        loop {
+            let path = self.path(date, index);
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if size >= max_size {
+                index += 1;
+                continue;
+            }
+
+            return match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => {
+                    debug!("writing audit log to {}", path.display());
+                    Some(AuditFile {
+                        date,
+                        index,
+                        size,
+                        file,
+                    })
+                }
+                Err(e) => {
+                    error!("cannot open audit log {}: {}", path.display(), e);
+                    None
+                }
+            };
+        }
+    }
+
+    fn remove_expired(&self, today: NaiveDate) {
+        let oldest = today - Duration::days(self.config.retention_days() as i64);
+        let entries = match fs::read_dir(self.config.dir()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("cannot read audit dir: {}", e);
+                return;
+            }
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let date = name
+                .strip_prefix("audit-")
+                .filter(|_| name.ends_with(".jsonl"))
+                .and_then(|rest| rest.get(..10))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+
+            if matches!(date, Some(date) if date < oldest) {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => info!("removed expired audit log {}", name),
+                    Err(e) => error!("cannot remove audit log {}: {}", name, e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// Log enabled in an empty dir named after the test, with 1 MB files
+    /// kept 7 days.
+    fn audit_log(name: &str) -> AuditLog {
+        let dir = std::env::temp_dir().join(format!("restoai-audit-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        AuditLog::new(&AuditConfig {
+            enabled: true,
+            dir: Some(dir.display().to_string()),
+            max_file_size: Some(1),
+            retention_days: Some(7),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn reopen(log: &AuditLog) -> AuditLog {
+        AuditLog::new(&log.config).unwrap()
+    }
+
+    fn entry(log: &AuditLog) -> AuditEntry {
+        log.start(
+            "/v1/chat/completions",
+            &PromptContext::default(),
+            "assistant",
+            false,
+        )
+    }
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap()
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path)
+            .map(|text| text.lines().count())
+            .unwrap_or_default()
+    }
+
+    /// Names of the files in the dir of `log`, sorted.
+    fn files(log: &AuditLog) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(log.config.dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn entries_are_json_lines_in_the_file_of_the_day() {
+        let log = audit_log("daily");
+        log.write_on(&entry(&log), day(10));
+        log.write_on(&entry(&log), day(10));
+        log.write_on(&entry(&log), day(11));
+
+        assert_eq!(
+            files(&log),
+            ["audit-2024-03-10.jsonl", "audit-2024-03-11.jsonl"]
+        );
+        assert_eq!(lines(&log.path(day(10), 0)), 2);
+        assert_eq!(lines(&log.path(day(11), 0)), 1);
+        let text = fs::read_to_string(log.path(day(11), 0)).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(text.trim()).unwrap();
+        assert_eq!(entry["endpoint"], "/v1/chat/completions");
+    }
+
+    #[test]
+    fn full_files_are_continued_in_the_next_one() {
+        let log = audit_log("size");
+        log.write_on(&entry(&log), day(10));
+        // grow the current file to just under the limit
+        let first = log.path(day(10), 0);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&first)
+            .unwrap()
+            .set_len(MB - 10)
+            .unwrap();
+        log.current.lock().as_mut().unwrap().size = MB - 10;
+
+        log.write_on(&entry(&log), day(10));
+        log.write_on(&entry(&log), day(10));
+        assert_eq!(fs::metadata(&first).unwrap().len(), MB - 10);
+        assert_eq!(lines(&log.path(day(10), 1)), 2);
+        assert_eq!(
+            files(&log),
+            ["audit-2024-03-10.1.jsonl", "audit-2024-03-10.jsonl"]
+        );
+    }
+
+    #[test]
+    fn files_of_the_day_are_appended_to_after_a_restart() {
+        let log = audit_log("restart");
+        log.write_on(&entry(&log), day(10));
+        let restarted = reopen(&log);
+        restarted.write_on(&entry(&restarted), day(10));
+        assert_eq!(lines(&log.path(day(10), 0)), 2);
+
+        // unless they are full
+        File::create(log.path(day(10), 0))
+            .unwrap()
+            .set_len(MB)
+            .unwrap();
+        let restarted = reopen(&log);
+        restarted.write_on(&entry(&restarted), day(10));
+        assert_eq!(lines(&log.path(day(10), 1)), 1);
+    }
+
+    #[test]
+    fn files_past_the_retention_are_removed_on_rotation() {
+        let log = audit_log("retention");
+        let dir = PathBuf::from(log.config.dir());
+        for name in [
+            "audit-2024-03-02.jsonl",
+            "audit-2024-03-02.1.jsonl",
+            "audit-2024-03-03.jsonl",
+            "audit-latest.jsonl",
+            "notes-2024-03-01.txt",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        log.write_on(&entry(&log), day(10));
+        assert_eq!(
+            files(&log),
+            [
+                "audit-2024-03-03.jsonl",
+                "audit-2024-03-10.jsonl",
+                "audit-latest.jsonl",
+                "notes-2024-03-01.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_logs_write_nothing() {
+        let dir = std::env::temp_dir().join("restoai-audit-disabled");
+        let _ = fs::remove_dir_all(&dir);
+        let log = AuditLog::new(&AuditConfig {
+            dir: Some(dir.display().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        log.write_on(&entry(&log), day(10));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn an_unusable_dir_is_an_error() {
+        let file = std::env::temp_dir().join("restoai-audit-file");
+        fs::write(&file, "").unwrap();
+        let error = AuditLog::new(&AuditConfig {
+            enabled: true,
+            dir: Some(file.join("audit").display().to_string()),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error.starts_with("Cannot create audit dir"), "{}", error);
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! The caller of an API request, authenticated by its bearer token: an API
+//! key of the config, or a JWT of the `[jwt]` identity provider.
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::ErrorUnauthorized,
+    http::header::ORIGIN,
+    FromRequest, HttpMessage, HttpRequest,
+};
+use chrono::{SecondsFormat, Utc};
+use std::{
+    future::{ready, Ready},
+    net::IpAddr,
+};
+
+use crate::{
+    config::{self, ApiKey, Config},
+    error::ApiError,
+    jwt::{self, JwksCache},
+};
+
+/// Set on the request by the bearer validator.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// What the threads, usage and rate limit of the caller are kept under:
+    /// the name of an API key, `jwt:<subject>` for a JWT. Never the secret,
+    /// so rotating a key keeps them.
+    pub id: String,
+    /// The API key, or the one made from the claims of the JWT.
+    pub key: ApiKey,
+}
+
+impl Caller {
+    /// Caller of a configured API key.
+    pub fn of_key(key: &ApiKey) -> Caller {
+        Caller {
+            id: key.name.clone(),
+            key: key.clone(),
+        }
+    }
+
+    pub async fn authenticate(config: &Config, jwks: &JwksCache, token: &str) -> Option<Caller> {
+        if token.is_empty() {
+            return None;
+        }
+        if let Some(key) = config.api_key(token) {
+            return Some(Caller::of_key(key));
+        }
+
+        let jwt = config.jwt.as_ref().filter(|_| jwt::is_jwt(token))?;
+        match jwt::verify(jwks, jwt, token).await {
+            Ok(key) => Some(Caller {
+                id: format!("{}{}", jwt::ID_PREFIX, key.name),
+                key,
+            }),
+            Err(e) => {
+                warn!("JWT rejected: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Address of the client, taken from `X-Forwarded-For` behind the trusted
+/// proxies. Connections over a unix socket come from a local proxy.
+fn client_ip(req: &ServiceRequest, config: &Config) -> Option<IpAddr> {
+    let proxies: Vec<_> = config
+        .auth
+        .trusted_proxies
+        .iter()
+        .filter_map(|range| config::parse_ip_range(range).ok())
+        .collect();
+    let trusted = |ip: &IpAddr| proxies.iter().any(|range| range.contains(ip));
+
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if let Some(peer) = peer.filter(|ip| !trusted(ip)) {
+        return Some(peer);
+    }
+    // the last address a trusted proxy did not add is the client
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for address in forwarded.iter().rev() {
+        match address.trim().parse::<IpAddr>() {
+            Ok(ip) if trusted(&ip) => continue,
+            Ok(ip) => return Some(ip),
+            Err(_) => return None,
+        }
+    }
+    peer
+}
+
+/// Checks the validity period of the key, and the client address and page
+/// origin of the request.
+pub fn check_restrictions(
+    req: &ServiceRequest,
+    config: &Config,
+    key: &ApiKey,
+) -> Result<(), ApiError> {
+    let restrictions = &key.restrictions;
+    let now = Utc::now();
+    if let Some(expires_at) = restrictions.expires_at.filter(|at| *at <= now) {
+        return Err(ApiError::KeyExpired(format!(
+            "API key expired at {}",
+            expires_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )));
+    }
+    if let Some(not_before) = restrictions.not_before.filter(|at| *at > now) {
+        return Err(ApiError::KeyNotYetValid(format!(
+            "API key is not valid before {}",
+            not_before.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )));
+    }
+
+    if restrictions.allowed_ips.is_some() {
+        let ip = client_ip(req, config);
+        if !restrictions.allows_ip(ip) {
+            return Err(ApiError::IpNotAllowed(match ip {
+                Some(ip) => format!("API key is not allowed from {}", ip),
+                None => "API key is not allowed from an unknown address".into(),
+            }));
+        }
+    }
+
+    let origin = req.headers().get(ORIGIN).and_then(|v| v.to_str().ok());
+    if let Some(origin) = origin.filter(|origin| !restrictions.allows_origin(origin)) {
+        return Err(ApiError::OriginNotAllowed(format!(
+            "API key is not allowed from origin '{}'",
+            origin
+        )));
+    }
+    Ok(())
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Caller>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Unauthorized")),
+        )
+    }
+}
+
+/// Name of the caller with the id, for reports. `None` for keys deleted
+/// since.
+pub fn caller_name(config: &Config, id: &str) -> Option<String> {
+    match id.strip_prefix(jwt::ID_PREFIX) {
+        Some(subject) => Some(subject.to_string()),
+        None => config
+            .api_keys
+            .iter()
+            .any(|key| key.name == id)
+            .then(|| id.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::Secret;
+    use actix_web::test::TestRequest;
+    use chrono::Duration;
+
+    fn config(trusted_proxies: &[&str]) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+llm_backend = "openai"
+llm_api_url = "https://api.openai.com/v1"
+llm_model_name = "gpt-3.5-turbo"
+api_keys = []
+"#,
+        )
+        .unwrap();
+        config.auth.trusted_proxies = trusted_proxies.iter().map(|p| p.to_string()).collect();
+        config
+    }
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> ServiceRequest {
+        let mut req = TestRequest::default().peer_addr(format!("{}:4000", peer).parse().unwrap());
+        if let Some(forwarded_for) = forwarded_for {
+            req = req.insert_header(("x-forwarded-for", forwarded_for));
+        }
+        req.to_srv_request()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn client_ip_is_the_peer_without_trusted_proxies() {
+        let config = config(&[]);
+        let req = request("203.0.113.7", Some("10.0.0.1"));
+        assert_eq!(client_ip(&req, &config), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_for_of_untrusted_peers() {
+        let config = config(&["10.0.0.0/8"]);
+        let req = request("203.0.113.7", Some("198.51.100.1"));
+        assert_eq!(client_ip(&req, &config), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn client_ip_is_the_last_address_not_added_by_a_trusted_proxy() {
+        let config = config(&["10.0.0.0/8", "127.0.0.1"]);
+        // spoofed first entry, client, then a second proxy
+        let req = request("127.0.0.1", Some("192.0.2.1, 198.51.100.1, 10.0.0.2"));
+        assert_eq!(client_ip(&req, &config), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn client_ip_reads_every_forwarded_for_header() {
+        let config = config(&["10.0.0.0/8"]);
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .append_header(("x-forwarded-for", "198.51.100.1"))
+            .append_header(("x-forwarded-for", "10.0.0.2"))
+            .to_srv_request();
+        assert_eq!(client_ip(&req, &config), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn client_ip_is_unknown_for_an_invalid_forwarded_address() {
+        let config = config(&["10.0.0.0/8"]);
+        let req = request("10.0.0.1", Some("unknown, 10.0.0.2"));
+        assert_eq!(client_ip(&req, &config), None);
+    }
+
+    #[test]
+    fn client_ip_falls_back_to_a_trusted_peer() {
+        let config = config(&["10.0.0.0/8"]);
+        assert_eq!(
+            client_ip(&request("10.0.0.1", None), &config),
+            ip("10.0.0.1")
+        );
+        let req = request("10.0.0.1", Some("10.0.0.3"));
+        assert_eq!(client_ip(&req, &config), ip("10.0.0.1"));
+    }
+
+    fn key(restrictions: config::KeyRestrictions) -> ApiKey {
+        ApiKey {
+            key: Secret::new("sk-test"),
+            name: "Key".into(),
+            description: None,
+            permissions: vec![],
+            language: None,
+            client_cert: None,
+            projects: vec![],
+            policy: Default::default(),
+            restrictions,
+        }
+    }
+
+    #[test]
+    fn expired_keys_are_refused() {
+        let config = config(&[]);
+        let req = request("203.0.113.7", None);
+        let expired = key(config::KeyRestrictions {
+            expires_at: Some(Utc::now() - Duration::minutes(1)),
+            ..Default::default()
+        });
+        assert!(matches!(
+            check_restrictions(&req, &config, &expired),
+            Err(ApiError::KeyExpired(_))
+        ));
+
+        let valid = key(config::KeyRestrictions {
+            expires_at: Some(Utc::now() + Duration::minutes(1)),
+            ..Default::default()
+        });
+        assert!(check_restrictions(&req, &config, &valid).is_ok());
+    }
+
+    #[test]
+    fn keys_are_refused_before_not_before() {
+        let config = config(&[]);
+        let req = request("203.0.113.7", None);
+        let early = key(config::KeyRestrictions {
+            not_before: Some(Utc::now() + Duration::minutes(1)),
+            ..Default::default()
+        });
+        assert!(matches!(
+            check_restrictions(&req, &config, &early),
+            Err(ApiError::KeyNotYetValid(_))
+        ));
+
+        let started = key(config::KeyRestrictions {
+            not_before: Some(Utc::now() - Duration::minutes(1)),
+            ..Default::default()
+        });
+        assert!(check_restrictions(&req, &config, &started).is_ok());
+    }
+
+    #[test]
+    fn allowed_ips_apply_to_the_forwarded_client() {
+        let config = config(&["10.0.0.0/8"]);
+        let restricted = key(config::KeyRestrictions {
+            allowed_ips: Some(vec!["198.51.100.0/24".into()]),
+            ..Default::default()
+        });
+        let allowed = request("10.0.0.1", Some("198.51.100.1"));
+        assert!(check_restrictions(&allowed, &config, &restricted).is_ok());
+        let refused = request("10.0.0.1", Some("192.0.2.1"));
+        assert!(matches!(
+            check_restrictions(&refused, &config, &restricted),
+            Err(ApiError::IpNotAllowed(_))
+        ));
+    }
+}
@@ -10,24 +10,1987 @@
 // is strictly forbidden unless prior written permission is obtained
 // from Neuversity.
 
-use serde::Deserialize;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use derive_more::Display;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{jwt, secret::Secret};
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct Config {
-    pub listen: Option<String>, // 127.0.0.1:8080
-    pub openai_api_key: Option<String>,
+    pub listen: Option<String>, // 127.0.0.1:8080 or unix:/run/restoai.sock
+    /// Serves HTTPS when set.
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Lets web pages of other origins call the server from the browser.
+    pub cors: Option<CorsConfig>,
+    pub openai_api_key: Option<Secret>,
     pub api_keys: ApiKeys,
+    /// Accepts JWTs of an identity provider besides the API keys.
+    pub jwt: Option<JwtConfig>,
+    /// Tenants, API keys belong to their projects.
+    #[serde(default)]
+    pub organizations: Vec<Organization>,
     pub llm_backend: String,
     pub llm_api_url: String,
     pub llm_model_name: String,
+    /// Per-backend settings by backend name, e.g. `[backends.openai]`.
+    #[serde(default)]
+    pub backends: HashMap<String, BackendConfig>,
+    pub llm_context_window: Option<u32>, // tokens, default 4096
+    #[serde(default)]
+    pub models: Vec<UpstreamModel>,
+    #[serde(default)]
+    pub context: ContextConfig,
+    #[serde(default)]
+    pub tokenizers: Vec<TokenizerConfig>,
+    /// Personas exposed as models, the built-in ones are used when empty.
+    #[serde(default)]
+    pub personas: Vec<Persona>,
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+    #[serde(default)]
+    pub injection: InjectionConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+lazy_static! {
+    static ref BUILTIN_PERSONAS: Vec<Persona> = vec![
+        Persona {
+            name: "programmer".into(),
+            system_prompt: "You are top notch software engineer in the world, you can give recommendation and best practice in programming and will give concise \
+                and optimized code example when needed.".into(),
+            system_prompt_policy: SystemPromptPolicy::Replace,
+            max_system_length: None,
+            vars: HashMap::new(),
+            language: Some(LanguagePolicy::MatchUser),
+            redact: None,
+            moderate: None,
+            injection_policy: None,
+        },
+        Persona {
+            name: "sysadmin".into(),
+            system_prompt: "You are top notch sysadmin in the world, you can give recommendation and best practice in system administration and devops, and will give \
+                concise and optimized code example when needed.".into(),
+            system_prompt_policy: SystemPromptPolicy::Replace,
+            max_system_length: None,
+            vars: HashMap::new(),
+            language: Some(LanguagePolicy::MatchUser),
+            redact: None,
+            moderate: None,
+            injection_policy: None,
+        },
+    ];
+}
+
+impl Config {
+    /// A copy with every credential masked, for printing.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        config.openai_api_key = config.openai_api_key.as_ref().map(Secret::masked);
+        for api_key in &mut config.api_keys {
+            api_key.key = api_key.key.masked();
+        }
+        for backend in config.backends.values_mut() {
+            backend.api_key = backend.api_key.as_ref().map(Secret::masked);
+        }
+        config
+    }
+
+    /// Checks what the TOML types alone cannot express.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut issue =
+            |path: String, message: String| issues.push(ConfigIssue::new(&path, &message));
+
+        if !KNOWN_BACKENDS.contains(&self.llm_backend.as_str()) {
+            issue(
+                "llm_backend".into(),
+                format!(
+                    "unknown backend `{}`, expected one of: {}",
+                    self.llm_backend,
+                    KNOWN_BACKENDS.join(", ")
+                ),
+            );
+        }
+
+        let listen = self.listen.as_deref().map(Listen::parse);
+        if let Some(Err(e)) = &listen {
+            issue("listen".into(), e.clone());
+        }
+
+        if let Some(tls) = &self.tls {
+            if let Some(Ok(Listen::Unix(_))) = listen {
+                issue("tls".into(), "TLS is not supported on a unix socket".into());
+            }
+            let files = [
+                ("cert", Some(&tls.cert)),
+                ("key", Some(&tls.key)),
+                ("client_ca", tls.client_ca.as_ref()),
+            ];
+            for (name, file) in files
+                .iter()
+                .filter_map(|(name, file)| Some((name, (*file)?)))
+            {
+                if let Err(e) = fs::metadata(file) {
+                    issue(
+                        format!("tls.{}", name),
+                        format!("cannot read `{}`: {}", file, e),
+                    );
+                }
+            }
+        }
+
+        for (i, key) in self.api_keys.iter().enumerate() {
+            if let Some(fingerprint) = &key.client_cert {
+                let hex = fingerprint.replace(':', "");
+                if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    issue(
+                        format!("api_keys[{}].client_cert", i),
+                        "expected the SHA-256 fingerprint of a certificate".into(),
+                    );
+                }
+            }
+            for (j, project) in key.projects.iter().enumerate() {
+                if self.project(project).is_none() {
+                    issue(
+                        format!("api_keys[{}].projects[{}]", i, j),
+                        format!("unknown project `{}`", project),
+                    );
+                }
+            }
+            self.validate_policy(&format!("api_keys[{}]", i), &key.policy, &mut issue);
+            let restrictions = &key.restrictions;
+            if let (Some(not_before), Some(expires_at)) =
+                (restrictions.not_before, restrictions.expires_at)
+            {
+                if not_before >= expires_at {
+                    issue(
+                        format!("api_keys[{}].not_before", i),
+                        "must be before `expires_at`".into(),
+                    );
+                }
+            }
+            for (j, range) in restrictions.allowed_ips.iter().flatten().enumerate() {
+                if let Err(e) = parse_ip_range(range) {
+                    issue(format!("api_keys[{}].allowed_ips[{}]", i, j), e);
+                }
+            }
+            for (j, origin) in restrictions.allowed_origins.iter().flatten().enumerate() {
+                if !is_origin(origin) {
+                    issue(
+                        format!("api_keys[{}].allowed_origins[{}]", i, j),
+                        format!(
+                            "expected an origin like `https://example.com`, got `{}`",
+                            origin
+                        ),
+                    );
+                }
+            }
+            // threads, usage and rate limits are kept by key name
+            if self.api_keys[..i].iter().any(|k| k.name == key.name) {
+                issue(
+                    format!("api_keys[{}].name", i),
+                    format!("duplicate API key name `{}`", key.name),
+                );
+            }
+            if key.name.starts_with(jwt::ID_PREFIX) {
+                issue(
+                    format!("api_keys[{}].name", i),
+                    format!("must not start with `{}`", jwt::ID_PREFIX),
+                );
+            }
+            // never print the key itself
+            if let Some(other) = self.api_keys[..i].iter().find(|k| k.key == key.key) {
+                issue(
+                    format!("api_keys[{}].key", i),
+                    format!("duplicate API key, `{}` uses the same one", other.name),
+                );
+            }
+        }
+
+        if let Some(cors) = &self.cors {
+            for (i, origin) in cors.allowed_origins.iter().enumerate() {
+                if origin != "*" && !is_origin(origin) {
+                    issue(
+                        format!("cors.allowed_origins[{}]", i),
+                        format!(
+                            "expected `*` or an origin like `https://example.com`, got `{}`",
+                            origin
+                        ),
+                    );
+                }
+            }
+            for (i, method) in cors.allowed_methods.iter().flatten().enumerate() {
+                if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphabetic()) {
+                    issue(
+                        format!("cors.allowed_methods[{}]", i),
+                        format!("invalid method `{}`", method),
+                    );
+                }
+            }
+        }
+
+        if let Some(level) = &self.log.level {
+            if let Err(e) = tracing_subscriber::EnvFilter::try_new(level) {
+                issue(
+                    "log.level".into(),
+                    format!("invalid filter `{}`: {}", level, e),
+                );
+            }
+        }
+
+        for (i, range) in self.auth.trusted_proxies.iter().enumerate() {
+            if let Err(e) = parse_ip_range(range) {
+                issue(format!("auth.trusted_proxies[{}]", i), e);
+            }
+        }
+
+        if let Some(jwt) = &self.jwt {
+            if jwt.issuer.trim().is_empty() {
+                issue("jwt.issuer".into(), "missing issuer".into());
+            }
+            if jwt.audience.is_empty() {
+                issue("jwt.audience".into(), "missing audience".into());
+            }
+            if jwt.jwks.starts_with("http://") {
+                issue(
+                    "jwt.jwks".into(),
+                    "the JWKS must be fetched over https, its keys sign the tokens".into(),
+                );
+            } else if !jwt.jwks_is_url() {
+                if let Err(e) = fs::metadata(&jwt.jwks) {
+                    issue(
+                        "jwt.jwks".into(),
+                        format!("cannot read `{}`: {}", jwt.jwks, e),
+                    );
+                }
+            }
+            for (j, project) in jwt.projects.iter().enumerate() {
+                if self.project(project).is_none() {
+                    issue(
+                        format!("jwt.projects[{}]", j),
+                        format!("unknown project `{}`", project),
+                    );
+                }
+            }
+            self.validate_policy("jwt", &jwt.policy, &mut issue);
+        }
+
+        for (i, organization) in self.organizations.iter().enumerate() {
+            let path = format!("organizations[{}]", i);
+            if self.organizations[..i]
+                .iter()
+                .any(|o| o.id == organization.id)
+            {
+                issue(
+                    format!("{}.id", path),
+                    format!("duplicate organization `{}`", organization.id),
+                );
+            }
+            self.validate_policy(&path, &organization.policy, &mut issue);
+            for (j, project) in organization.projects.iter().enumerate() {
+                let path = format!("{}.projects[{}]", path, j);
+                // the first one with the id, possibly in another organization
+                let first = self.project(&project.id).map(|(_, p)| p);
+                if !first.is_some_and(|p| std::ptr::eq(p, project)) {
+                    issue(
+                        format!("{}.id", path),
+                        format!("duplicate project `{}`", project.id),
+                    );
+                }
+                self.validate_policy(&path, &project.policy, &mut issue);
+            }
+        }
+
+        let tokenizers: Vec<&str> = self.tokenizers.iter().map(|t| t.name.as_str()).collect();
+        for (i, name) in tokenizers.iter().enumerate() {
+            if tokenizers[..i].contains(name) {
+                issue(
+                    format!("tokenizers[{}].name", i),
+                    format!("duplicate tokenizer `{}`", name),
+                );
+            }
+        }
+
+        for (i, model) in self.models.iter().enumerate() {
+            if self.models[..i].iter().any(|m| m.name == model.name) {
+                issue(
+                    format!("models[{}].name", i),
+                    format!("duplicate model `{}`", model.name),
+                );
+            }
+            if let Some(tokenizer) = &model.tokenizer {
+                if !tokenizers.contains(&tokenizer.as_str()) {
+                    issue(
+                        format!("models[{}].tokenizer", i),
+                        format!("unknown tokenizer `{}`", tokenizer),
+                    );
+                }
+            }
+            for (field, price) in [
+                ("input_price", model.input_price),
+                ("output_price", model.output_price),
+            ] {
+                if price.is_some_and(|p| !p.is_finite() || p < 0.0) {
+                    issue(
+                        format!("models[{}].{}", i, field),
+                        "must be a number of at least 0".into(),
+                    );
+                }
+            }
+        }
+
+        for (i, persona) in self.personas.iter().enumerate() {
+            if self.personas[..i].iter().any(|p| p.name == persona.name) {
+                issue(
+                    format!("personas[{}].name", i),
+                    format!("duplicate persona `{}`", persona.name),
+                );
+            }
+            if persona.system_prompt.trim().is_empty() {
+                issue(
+                    format!("personas[{}].system_prompt", i),
+                    format!("missing system prompt for `{}`", persona.name),
+                );
+            } else if let Err(e) =
+                minijinja::Environment::new().template_from_str(&persona.system_prompt)
+            {
+                issue(
+                    format!("personas[{}].system_prompt", i),
+                    format!("invalid template for `{}`: {}", persona.name, e),
+                );
+            }
+        }
+
+        if self.context.strategy == TruncationStrategy::Summarize
+            && self.context.summarize_model.is_none()
+        {
+            issue(
+                "context.strategy".into(),
+                "the `summarize` strategy needs a `summarize_model`".into(),
+            );
+        }
+
+        for (i, pattern) in self.moderation.patterns.iter().enumerate() {
+            if let Err(e) = regex::Regex::new(pattern) {
+                issue(format!("moderation.patterns[{}]", i), e.to_string());
+            }
+        }
+
+        for (i, rule) in self.injection.rules.iter().enumerate() {
+            if let Err(e) = regex::Regex::new(&rule.pattern) {
+                issue(format!("injection.rules[{}].pattern", i), e.to_string());
+            }
+        }
+        if let Some(threshold) = self.injection.threshold {
+            if !(0.0..=1.0).contains(&threshold) {
+                issue(
+                    "injection.threshold".into(),
+                    "must be between 0 and 1".into(),
+                );
+            }
+        }
+
+        issues
+    }
+
+    /// Settings of the named backend, unset values taken from the top-level
+    /// `llm_api_url`, `llm_model_name` and `openai_api_key`.
+    pub fn backend(&self, name: &str) -> UpstreamBackend {
+        let backend = self.backends.get(name).cloned().unwrap_or_default();
+        UpstreamBackend {
+            api_url: backend.api_url.unwrap_or_else(|| self.llm_api_url.clone()),
+            api_key: backend.api_key.or_else(|| self.openai_api_key.clone()),
+            model: backend.model.unwrap_or_else(|| self.llm_model_name.clone()),
+            organization: backend.organization,
+            project: backend.project,
+        }
+    }
+
+    pub fn personas(&self) -> &[Persona] {
+        if self.personas.is_empty() {
+            &BUILTIN_PERSONAS
+        } else {
+            &self.personas
+        }
+    }
+
+    pub fn api_key(&self, token: &str) -> Option<&ApiKey> {
+        self.api_keys.iter().find(|key| key.key.matches(token))
+    }
+
+    /// The API key a client certificate is mapped to, by its SHA-256
+    /// fingerprint.
+    pub fn api_key_by_cert(&self, fingerprint: &str) -> Option<&ApiKey> {
+        self.api_keys.iter().find(|key| {
+            key.client_cert
+                .as_ref()
+                .is_some_and(|cert| cert.replace(':', "").eq_ignore_ascii_case(fingerprint))
+        })
+    }
+
+    pub fn persona(&self, name: &str) -> Option<&Persona> {
+        self.personas().iter().find(|p| p.name == name)
+    }
+
+    fn validate_policy(
+        &self,
+        path: &str,
+        policy: &KeyPolicy,
+        issue: &mut impl FnMut(String, String),
+    ) {
+        if let Some(budget) = &policy.budget {
+            if !budget.monthly.is_finite() || budget.monthly < 0.0 {
+                issue(
+                    format!("{}.budget.monthly", path),
+                    "must be a number of at least 0".into(),
+                );
+            }
+        }
+        for (i, persona) in policy.personas.iter().flatten().enumerate() {
+            if self.persona(persona).is_none() {
+                issue(
+                    format!("{}.personas[{}]", path, i),
+                    format!("unknown persona `{}`", persona),
+                );
+            }
+        }
+        if policy.requests_per_minute == Some(0) {
+            issue(
+                format!("{}.requests_per_minute", path),
+                "must be at least 1".into(),
+            );
+        }
+        if policy.max_tokens == Some(0) {
+            issue(format!("{}.max_tokens", path), "must be at least 1".into());
+        }
+    }
+
+    /// A project by id, with its organization.
+    pub fn project(&self, id: &str) -> Option<(&Organization, &Project)> {
+        self.organizations.iter().find_map(|organization| {
+            organization
+                .projects
+                .iter()
+                .find(|p| p.id == id)
+                .map(|project| (organization, project))
+        })
+    }
+
+    /// Startup overview of the config, without any secret.
+    pub fn summary(&self) -> String {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        let backend = self.backend(&self.llm_backend);
+        let names = |names: Vec<&str>| names.join(", ");
+        [
+            format!(
+                "listen: {}{}",
+                self.listen.as_deref().unwrap_or("default"),
+                match &self.tls {
+                    Some(tls) if tls.client_ca.is_some() => " (TLS, client certificates)",
+                    Some(_) => " (TLS)",
+                    None => "",
+                }
+            ),
+            format!(
+                "backend: {} at {} ({})",
+                self.llm_backend, backend.api_url, backend.model
+            ),
+            format!(
+                "upstream API key: {}",
+                if backend.api_key.is_some() {
+                    "from config"
+                } else {
+                    "from environment"
+                }
+            ),
+            format!(
+                "API keys: {} ({})",
+                self.api_keys.len(),
+                names(self.api_keys.iter().map(|k| k.name.as_str()).collect())
+            ),
+            format!(
+                "JWT: {}",
+                match &self.jwt {
+                    Some(jwt) => format!("{} ({})", jwt.issuer, jwt.jwks),
+                    None => "off".into(),
+                }
+            ),
+            format!(
+                "organizations: {} ({} projects)",
+                self.organizations.len(),
+                self.organizations
+                    .iter()
+                    .map(|o| o.projects.len())
+                    .sum::<usize>()
+            ),
+            format!(
+                "personas: {}",
+                names(self.personas().iter().map(|p| p.name.as_str()).collect())
+            ),
+            format!(
+                "guardrails: redaction {}, moderation {}, injection {}",
+                on_off(self.redaction.enabled),
+                on_off(self.moderation.enabled),
+                on_off(self.injection.enabled)
+            ),
+            format!("audit: {}", on_off(self.audit.enabled)),
+            format!("log: {}, {}", self.log.format, self.log.level()),
+        ]
+        .iter()
+        .map(|line| format!("  {}", line))
+        .fold("Config:".to_string(), |summary, line| {
+            summary + "\n" + &line
+        })
+    }
+
+    pub fn upstream_model(&self, name: &str) -> Option<&UpstreamModel> {
+        self.models.iter().find(|m| m.name == name)
+    }
+
+    /// Cost in USD of the tokens on the given upstream model, `None` when it
+    /// has no prices.
+    pub fn cost(
+        &self,
+        upstream_model: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) -> Option<f64> {
+        let model = self.upstream_model(upstream_model)?;
+        if model.input_price.is_none() && model.output_price.is_none() {
+            return None;
+        }
+        Some(
+            (prompt_tokens as f64 * model.input_price.unwrap_or_default()
+                + completion_tokens as f64 * model.output_price.unwrap_or_default())
+                / 1000.0,
+        )
+    }
+
+    /// Context window of the given upstream model, falling back to
+    /// `llm_context_window`.
+    pub fn context_window(&self, upstream_model: &str) -> u32 {
+        self.upstream_model(upstream_model)
+            .and_then(|m| m.context_window)
+            .or(self.llm_context_window)
+            .unwrap_or(4096)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
-pub struct ApiKey {
+pub struct Persona {
+    pub name: String,
+    /// MiniJinja template, see `vars` for the available variables.
+    pub system_prompt: String,
+    /// What to do with system messages sent by the client.
+    #[serde(default)]
+    pub system_prompt_policy: SystemPromptPolicy,
+    /// Maximum length (in characters) of the client system text.
+    pub max_system_length: Option<usize>,
+    /// Default template variables, overridden by the request `metadata` and
+    /// `x-restoai-var-*` headers. `date`, `datetime`, `key_name`, `user` and
+    /// `model` are always provided.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// `fixed:<lang>` or `match_user`, no language instruction when unset.
+    pub language: Option<LanguagePolicy>,
+    /// Enables or disables PII redaction for this persona, overriding
+    /// `redaction.enabled`.
+    pub redact: Option<bool>,
+    /// Enables or disables output moderation for this persona, overriding
+    /// `moderation.enabled`.
+    pub moderate: Option<bool>,
+    /// Prompt injection policy for this persona, overriding
+    /// `injection.policy`. Checks are enabled when set, `off` disables them.
+    pub injection_policy: Option<InjectionPolicy>,
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` from the environment, `$$` is a
+/// literal `$`. A value that is then `file:<path>` is replaced by the file
+/// content, e.g. `file:/run/secrets/openai_api_key`.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| "unterminated `${`".to_string())?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match (env::var(name), default) {
+                (Ok(var), _) => expanded.push_str(&var),
+                (Err(_), Some(default)) => expanded.push_str(default),
+                (Err(_), None) => {
+                    return Err(format!("environment variable `{}` is not set", name))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    match expanded.strip_prefix("file:") {
+        Some(path) => fs::read_to_string(path)
+            .map(|content| content.trim_end().to_string())
+            .map_err(|e| format!("cannot read `{}`: {}", path, e)),
+        None => Ok(expanded),
+    }
+}
+
+fn interpolate_value(value: &mut toml::Value, path: &str) -> Result<(), ConfigIssue> {
+    match value {
+        toml::Value::String(s) => {
+            *s = interpolate(s).map_err(|e| ConfigIssue::new(path, &e))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, &format!("{}[{}]", path, i))?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                let path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                interpolate_value(item, &path)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+const KNOWN_BACKENDS: &[&str] = &["openai"];
+
+/// A config error, at a TOML path like `api_keys[1].key` when known.
+#[derive(Debug, Display)]
+#[display(fmt = "{}", message)]
+pub struct ConfigIssue {
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(path: &str, message: &str) -> Self {
+        ConfigIssue {
+            path: (!path.is_empty()).then(|| path.to_string()),
+            message: message.to_string(),
+        }
+    }
+}
+
+const CONF_DIR: &str = "conf.d";
+
+const ENV_PREFIX: &str = "RESTOAI_";
+
+/// Top-level keys without a default. Fragments may leave them to other files.
+const REQUIRED_KEYS: &[&str] = &["api_keys", "llm_backend", "llm_api_url", "llm_model_name"];
+
+/// One source of config values, see [`load`].
+struct Layer {
+    /// File path or environment variable name.
+    source: String,
+    /// Content of a file, to position errors.
+    text: Option<String>,
+    value: toml::Value,
+}
+
+impl Layer {
+    fn file(source: String, text: String) -> Result<Self, String> {
+        let at = |offset: Option<usize>| match offset {
+            Some(offset) => format!("{}:{}", source, text[..offset].matches('\n').count() + 1),
+            None => source.clone(),
+        };
+
+        // syntax and type errors, positioned by the TOML parser itself
+        if let Err(e) = toml::from_str::<Config>(&text) {
+            let missing_required = REQUIRED_KEYS
+                .iter()
+                .any(|key| e.message() == format!("missing field `{}`", key));
+            if !missing_required {
+                return Err(format!(
+                    "{}: {}",
+                    at(e.span().map(|s| s.start)),
+                    e.message()
+                ));
+            }
+        }
+
+        let value = toml::from_str(&text).map_err(|e: toml::de::Error| {
+            format!("{}: {}", at(e.span().map(|s| s.start)), e.message())
+        })?;
+        Ok(Layer {
+            source,
+            text: Some(text),
+            value,
+        })
+    }
+
+    /// `RESTOAI_BACKENDS__OPENAI__MODEL=gpt-4o` sets `backends.openai.model`.
+    /// The value is read as a TOML literal like `true`, `8192` or `["a"]`, and
+    /// as a plain string otherwise.
+    fn env(name: &str, raw: &str) -> Self {
+        let mut value = toml::from_str::<toml::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(raw.to_string()));
+        for key in name[ENV_PREFIX.len()..].rsplit("__") {
+            let mut table = toml::Table::new();
+            table.insert(key.to_lowercase(), value);
+            value = toml::Value::Table(table);
+        }
+        Layer {
+            source: name.to_string(),
+            text: None,
+            value,
+        }
+    }
+
+    fn report(&self, issue: &ConfigIssue) -> String {
+        match (&issue.path, &self.text) {
+            (Some(path), Some(text)) => {
+                let source = match offset_of(text, path) {
+                    Some(offset) => {
+                        format!(
+                            "{}:{}",
+                            self.source,
+                            text[..offset].matches('\n').count() + 1
+                        )
+                    }
+                    None => self.source.clone(),
+                };
+                format!("{}: `{}`: {}", source, path, issue)
+            }
+            (Some(path), None) => format!("{}: `{}`: {}", self.source, path, issue),
+            (None, _) => format!("{}: {}", self.source, issue),
+        }
+    }
+}
+
+/// The `*.conf` and `*.toml` files of the `conf.d` directory next to the
+/// config at `path`, in name order.
+fn fragments(path: &str) -> Vec<PathBuf> {
+    let dir = Path::new(path)
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(CONF_DIR);
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_file()
+                    && matches!(
+                        path.extension().and_then(|ext| ext.to_str()),
+                        Some("conf" | "toml")
+                    )
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+fn env_overrides() -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| name.len() > ENV_PREFIX.len() && name.starts_with(ENV_PREFIX))
+        .collect();
+    vars.sort();
+    vars
+}
+
+/// Config sources of `path` in the order they are merged, see [`load`].
+pub fn sources(path: &str) -> Vec<String> {
+    std::iter::once(path.to_string())
+        .chain(
+            fragments(path)
+                .iter()
+                .map(|file| file.display().to_string()),
+        )
+        .chain(env_overrides().into_iter().map(|(name, _)| name))
+        .collect()
+}
+
+/// Merges `from` into `into`: tables key by key, arrays appended when
+/// `append` and replaced otherwise, other values replaced.
+fn merge(into: &mut toml::Value, from: toml::Value, append: bool) {
+    match (into, from) {
+        (toml::Value::Table(into), toml::Value::Table(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge(existing, value, append),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(into), toml::Value::Array(from)) if append => into.extend(from),
+        (into, from) => *into = from,
+    }
+}
+
+/// Reads, merges and validates the config at `path`, layered as:
+///
+/// 1. the file at `path`,
+/// 2. the fragments in `conf.d/` next to it, in name order, their arrays
+///    (`api_keys`, `personas`, ...) appended to the ones before,
+/// 3. `RESTOAI_*` environment variables, see [`Layer::env`].
+///
+/// Errors are reported as `<source>:<line>: <message>`.
+pub fn load(path: &str) -> Result<Config, Vec<String>> {
+    let mut layers = Vec::new();
+    let mut errors = Vec::new();
+    let files = std::iter::once(PathBuf::from(path)).chain(fragments(path));
+    for file in files {
+        let source = file.display().to_string();
+        match fs::read_to_string(&file)
+            .map_err(|e| format!("{}: {}", source, e))
+            .and_then(|text| Layer::file(source, text))
+        {
+            Ok(layer) => layers.push(layer),
+            Err(e) => errors.push(e),
+        }
+    }
+    layers.extend(
+        env_overrides()
+            .iter()
+            .map(|(name, raw)| Layer::env(name, raw)),
+    );
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut merged = toml::Value::Table(toml::Table::new());
+    for layer in &mut layers {
+        if let Err(issue) = interpolate_value(&mut layer.value, "") {
+            errors.push(layer.report(&issue));
+        }
+        merge(&mut merged, layer.value.clone(), layer.text.is_some());
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let config: Config = merged.try_into().map_err(|e: toml::de::Error| {
+        let source = blame_env(&layers, e.message()).unwrap_or(path);
+        vec![format!("{}: {}", source, e.message())]
+    })?;
+    let issues = config.validate();
+    if issues.is_empty() {
+        Ok(config)
+    } else {
+        Err(issues.iter().map(|issue| locate(&layers, issue)).collect())
+    }
+}
+
+/// The environment variable causing the type error `message`, the files
+/// being fine on their own.
+fn blame_env<'a>(layers: &'a [Layer], message: &str) -> Option<&'a str> {
+    let mut files = toml::Value::Table(toml::Table::new());
+    for layer in layers.iter().filter(|layer| layer.text.is_some()) {
+        merge(&mut files, layer.value.clone(), true);
+    }
+    let fails =
+        |value: toml::Value| matches!(value.try_into::<Config>(), Err(e) if e.message() == message);
+    if fails(files.clone()) {
+        return None;
+    }
+
+    layers
+        .iter()
+        .filter(|layer| layer.text.is_none())
+        .find(|layer| {
+            let mut value = files.clone();
+            merge(&mut value, layer.value.clone(), false);
+            fails(value)
+        })
+        .map(|layer| layer.source.as_str())
+}
+
+/// Reports `issue` at the last layer that sets its path. The index into the
+/// first array of the path is made relative to the layer the item came from.
+fn locate(layers: &[Layer], issue: &ConfigIssue) -> String {
+    let path = match &issue.path {
+        Some(path) => path,
+        None => return layers[0].report(issue),
+    };
+
+    let mut found = None;
+    match path.split_once('[') {
+        Some((array, rest)) => {
+            let (index, rest) = rest.split_once(']').unwrap_or((rest, ""));
+            let index = index.parse::<usize>().unwrap_or_default();
+            let mut start = 0;
+            for layer in layers {
+                let len = lookup(&layer.value, array)
+                    .and_then(toml::Value::as_array)
+                    .map_or(0, Vec::len);
+                // environment overrides replace arrays
+                if layer.text.is_none() && len > 0 {
+                    start = 0;
+                }
+                if (start..start + len).contains(&index) {
+                    found = Some((layer, format!("{}[{}]{}", array, index - start, rest)));
+                }
+                start += len;
+            }
+        }
+        None => {
+            found = layers
+                .iter()
+                .rev()
+                .find(|layer| lookup(&layer.value, path).is_some())
+                .map(|layer| (layer, path.clone()));
+        }
+    }
+
+    let (layer, path) = found.unwrap_or((&layers[0], path.clone()));
+    layer.report(&ConfigIssue {
+        path: Some(path),
+        message: issue.message.clone(),
+    })
+}
+
+/// The value at a path without array indexes, like `injection.threshold`.
+fn lookup<'a>(value: &'a toml::Value, path: &str) -> Option<&'a toml::Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+/// Byte offset of the value at `path` (`a.b[2].c`) in the TOML `text`, or of
+/// the closest parent that exists.
+fn offset_of(text: &str, path: &str) -> Option<usize> {
+    let document = toml_edit::ImDocument::parse(text).ok()?;
+    let mut item = document.as_item();
+    let mut offset = None;
+    for segment in path.split('.') {
+        let (key, indexes) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        let next = item.get(key);
+        let next = indexes
+            .split(['[', ']'])
+            .filter_map(|index| index.parse::<usize>().ok())
+            .fold(next, |item, index| item.and_then(|item| item.get(index)));
+        match next {
+            Some(next) => {
+                offset = next.span().map(|span| span.start).or(offset);
+                item = next;
+            }
+            None => break,
+        }
+    }
+    offset
+}
+
+/// Listen address, `<host>[:<port>]` or `unix:<path>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp { host: String, port: Option<u16> },
+    Unix(PathBuf),
+}
+
+impl Listen {
+    pub fn parse(listen: &str) -> Result<Self, String> {
+        if let Some(path) = listen.strip_prefix("unix:") {
+            return match path {
+                "" => Err("missing unix socket path".to_string()),
+                path => Ok(Listen::Unix(PathBuf::from(path))),
+            };
+        }
+
+        // `[::1]:8080`, `::1`, `host:8080` or `host`
+        let (host, port) = match listen.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .ok_or_else(|| format!("invalid listen address `{}`", listen))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None if listen.matches(':').count() == 1 => {
+                let (host, port) = listen.split_once(':').unwrap_or_default();
+                (host, Some(port))
+            }
+            None => (listen, None),
+        };
+        if host.is_empty() {
+            return Err(format!(
+                "invalid listen address `{}`, expected `<host>:<port>` or `unix:<path>`",
+                listen
+            ));
+        }
+        let port = port
+            .map(|port| port.parse::<u16>())
+            .transpose()
+            .map_err(|_| format!("invalid port in listen address `{}`", listen))?;
+        Ok(Listen::Tcp {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// `[auth]`, how each route scope authenticates. The API always needs a
+/// bearer token.
+#[derive(Deserialize, Debug, Clone, Serialize, Default, PartialEq)]
+pub struct AuthConfig {
+    /// `/healthz` and `/readyz`.
+    #[serde(default)]
+    pub health: ScopeAuth,
+    /// The web page at `/`.
+    #[serde(default)]
+    pub ui: ScopeAuth,
+    /// Addresses or CIDR ranges of reverse proxies, the client address of
+    /// their requests is taken from `X-Forwarded-For`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<String>,
+}
+
+/// `[cors]`, the origins, methods and headers browsers are told are allowed.
+/// An API key with `allowed_origins` narrows them for its requests.
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq)]
+pub struct CorsConfig {
+    /// Origins like `https://app.example.com`, `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// GET, POST and DELETE when unset.
+    pub allowed_methods: Option<Vec<String>>,
+    /// Request headers, the ones a preflight asks for when unset.
+    pub allowed_headers: Option<Vec<String>>,
+    /// Response headers pages may read, besides the `x-restoai-*` ones.
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// Seconds browsers may cache a preflight, 600 when unset.
+    pub max_age: Option<u32>,
+}
+
+impl CorsConfig {
+    pub fn allowed_methods(&self) -> Vec<String> {
+        match &self.allowed_methods {
+            Some(methods) => methods.iter().map(|m| m.to_uppercase()).collect(),
+            None => vec!["GET".into(), "POST".into(), "DELETE".into()],
+        }
+    }
+
+    pub fn max_age(&self) -> u32 {
+        self.max_age.unwrap_or(600)
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let origin = origin.trim_end_matches('/');
+        self.allowed_origins
+            .iter()
+            .any(|o| o == "*" || o.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopeAuth {
+    #[default]
+    Public,
+    Bearer,
+}
+
+/// `[tls]`, certificate and key are PEM files, reloaded when they change.
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first.
+    pub cert: String,
     pub key: String,
+    /// CA certificates of the client certificates, enables mutual TLS.
+    pub client_ca: Option<String>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// Seconds between checks of the certificate files, 60 when unset.
+    pub reload_interval: Option<u64>,
+}
+
+impl TlsConfig {
+    pub fn reload_interval(&self) -> u64 {
+        self.reload_interval.unwrap_or(60).max(1)
+    }
+}
+
+/// Whether clients must present a certificate signed by `client_ca`.
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// Clients without a certificate authenticate with a bearer token.
+    #[default]
+    Optional,
+    Required,
+}
+
+/// `[backends.<name>]`, overriding the top-level upstream settings.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct BackendConfig {
+    pub api_url: Option<String>,
+    pub api_key: Option<Secret>,
+    /// Upstream model the personas run on.
+    pub model: Option<String>,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+}
+
+/// Resolved settings of a backend, see [`Config::backend`].
+#[derive(Debug, Clone)]
+pub struct UpstreamBackend {
+    pub api_url: String,
+    pub api_key: Option<Secret>,
+    pub model: String,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+}
+
+/// Language the persona answers in.
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum LanguagePolicy {
+    /// Always answer in the given language, a name or ISO 639-3 code.
+    Fixed(String),
+    /// Answer in the language of the last user message.
+    MatchUser,
+}
+
+impl TryFrom<String> for LanguagePolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.split_once(':') {
+            Some(("fixed", language)) if !language.trim().is_empty() => {
+                Ok(LanguagePolicy::Fixed(language.trim().to_string()))
+            }
+            None if value == "match_user" => Ok(LanguagePolicy::MatchUser),
+            _ => Err(format!(
+                "invalid language `{}`, expected `fixed:<lang>` or `match_user`",
+                value
+            )),
+        }
+    }
+}
+
+impl From<LanguagePolicy> for String {
+    fn from(policy: LanguagePolicy) -> Self {
+        match policy {
+            LanguagePolicy::Fixed(language) => format!("fixed:{}", language),
+            LanguagePolicy::MatchUser => "match_user".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemPromptPolicy {
+    /// Drop client system messages, only the persona prompt is used.
+    #[default]
+    Replace,
+    /// Add the client system text after the persona prompt.
+    Append,
+    /// Add the client system text before the persona prompt.
+    Prepend,
+    /// Refuse requests containing system messages.
+    Reject,
+}
+
+/// PII redaction of prompts before they are sent upstream.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct RedactionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Rules to apply, all of them when unset.
+    pub rules: Option<Vec<RedactionKind>>,
+    /// Put the original values back into the response.
+    #[serde(default)]
+    pub restore: bool,
+}
+
+impl RedactionConfig {
+    pub fn rules(&self) -> Vec<RedactionKind> {
+        self.rules.clone().unwrap_or_else(|| {
+            // secrets and emails first, their digits must not be taken for
+            // card or phone numbers
+            vec![
+                RedactionKind::Secret,
+                RedactionKind::Email,
+                RedactionKind::CreditCard,
+                RedactionKind::Nik,
+                RedactionKind::Phone,
+            ]
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionKind {
+    #[display(fmt = "email")]
+    Email,
+    #[display(fmt = "phone")]
+    Phone,
+    /// Indonesian national ID number.
+    #[display(fmt = "nik")]
+    Nik,
+    #[display(fmt = "credit_card")]
+    CreditCard,
+    /// API keys and access tokens.
+    #[display(fmt = "secret")]
+    Secret,
+}
+
+/// Filtering of model output before it is returned to the client.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct ModerationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub action: ModerationAction,
+    /// Blocked words, matched case-insensitively as whole words.
+    #[serde(default)]
+    pub terms: Vec<String>,
+    /// Blocked regular expressions.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Replacement text in `mask` mode, `[filtered]` when unset.
+    pub mask: Option<String>,
+    /// Longest match (in bytes) detected across streamed chunks, the stream
+    /// is held back by this much. Defaults to 128.
+    pub max_match_length: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default, PartialEq, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Replace matches with the mask and carry on.
+    #[default]
+    #[display(fmt = "mask")]
+    Mask,
+    /// Cut the response off at the first match.
+    #[display(fmt = "block")]
+    Block,
+}
+
+/// Prompt injection and jailbreak detection on the client messages.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct InjectionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub policy: InjectionPolicy,
+    /// Score (0 to 1) from which a prompt is treated as an injection, 0.5
+    /// when unset.
+    pub threshold: Option<f32>,
+    /// Rules added to the built-in ones.
+    #[serde(default)]
+    pub rules: Vec<InjectionRule>,
+    /// Upstream model asked to score the last user message when the rules
+    /// alone stay below the threshold, usually a small and cheap one.
+    pub classifier_model: Option<String>,
+    /// System message added by the `reinforce` policy.
+    pub reminder: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct InjectionRule {
+    pub name: String,
+    /// Case-insensitive regular expression.
+    pub pattern: String,
+    /// Score of a match, 1.0 when unset.
+    pub weight: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, Default, PartialEq, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectionPolicy {
+    /// Log the attempt and forward the prompt as is.
+    #[default]
+    #[display(fmt = "flag")]
+    Flag,
+    /// Reject the request.
+    #[display(fmt = "block")]
+    Block,
+    /// Add a system message reminding the model of its instructions.
+    #[display(fmt = "reinforce")]
+    Reinforce,
+    /// No checks.
+    #[display(fmt = "off")]
+    Off,
+}
+
+/// `[log]`, read once at startup.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Filter like `info` or `restoai=debug,actix_web=warn`, `info` when
+    /// unset. `RUST_LOG` takes precedence.
+    pub level: Option<String>,
+}
+
+impl LogConfig {
+    pub fn level(&self) -> &str {
+        self.level.as_deref().unwrap_or("info")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, Default, PartialEq, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One human readable line per event.
+    #[default]
+    #[display(fmt = "text")]
+    Text,
+    /// One JSON object per event, with the fields of the request.
+    #[display(fmt = "json")]
+    Json,
+}
+
+/// JSON Lines audit trail of completion requests.
+#[derive(Deserialize, Debug, Clone, Serialize, Default, PartialEq)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Directory of the `audit-YYYY-MM-DD.jsonl` files, `audit` when unset.
+    pub dir: Option<String>,
+    /// Record the prompt sent upstream and the response returned, on by
+    /// default.
+    pub bodies: Option<bool>,
+    /// Size in MB after which a new file is started, 100 when unset.
+    pub max_file_size: Option<u64>,
+    /// Files older than this many days are deleted, 30 when unset.
+    pub retention_days: Option<u32>,
+}
+
+impl AuditConfig {
+    pub fn dir(&self) -> &str {
+        self.dir.as_deref().unwrap_or("audit")
+    }
+
+    pub fn bodies(&self) -> bool {
+        self.bodies.unwrap_or(true)
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(100) * 1024 * 1024
+    }
+
+    pub fn retention_days(&self) -> u32 {
+        self.retention_days.unwrap_or(30)
+    }
+}
+
+/// An entry in the upstream model catalog.
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct UpstreamModel {
+    pub name: String,
+    pub context_window: Option<u32>,
+    /// Name of an entry in `tokenizers`, token counts are estimated when unset.
+    pub tokenizer: Option<String>,
+    /// USD per 1K prompt tokens.
+    pub input_price: Option<f64>,
+    /// USD per 1K completion tokens.
+    pub output_price: Option<f64>,
+}
+
+/// A tiktoken-compatible BPE file.
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct TokenizerConfig {
+    pub name: String,
+    pub path: String,
+    /// Split regex, only required for encodings other than cl100k_base,
+    /// o200k_base, p50k_base and r50k_base.
+    pub pattern: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct ContextConfig {
+    #[serde(default)]
+    pub strategy: TruncationStrategy,
+    /// Messages kept verbatim by the `keep_last` and `summarize` strategies, default 4.
+    pub keep_last: Option<usize>,
+    /// Cheaper upstream model used by the `summarize` strategy.
+    pub summarize_model: Option<String>,
+    /// Tokens reserved for the completion, default 512.
+    pub reserve_tokens: Option<u32>,
+}
+
+impl ContextConfig {
+    pub fn reserve_tokens(&self) -> u32 {
+        self.reserve_tokens.unwrap_or(512)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default, PartialEq, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncationStrategy {
+    #[default]
+    #[display(fmt = "drop_oldest")]
+    DropOldest,
+    #[display(fmt = "keep_last")]
+    KeepLast,
+    #[display(fmt = "summarize")]
+    Summarize,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub key: Secret,
     pub name: String,
     pub description: Option<String>,
     pub permissions: Vec<String>,
+    /// Overrides the persona language for requests made with this key.
+    pub language: Option<LanguagePolicy>,
+    /// SHA-256 fingerprint of a client certificate authenticating as this
+    /// key, with `[tls] client_ca`.
+    pub client_cert: Option<String>,
+    /// Ids of the projects the key belongs to. The first one is used unless
+    /// the request picks another with the `OpenAI-Project` or
+    /// `OpenAI-Organization` header.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub projects: Vec<String>,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+    #[serde(flatten)]
+    pub restrictions: KeyRestrictions,
 }
 
 pub type ApiKeys = Vec<ApiKey>;
+
+/// Settings of an API key, which may also be set on its project and
+/// organization. Those the key leaves unset come from its project, then
+/// from the organization; each key gets its own rate limit, while a budget
+/// is shared by the keys of the project or organization it is set on.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct KeyPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+    /// Personas the key may use, all of them when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub personas: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    /// Caps the `max_tokens` of completions, and applies to requests without
+    /// one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Overrides `[audit] bodies`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_bodies: Option<bool>,
+}
+
+impl KeyPolicy {
+    /// This policy, with the settings it leaves unset taken from `parent`.
+    pub fn inherit(&self, parent: &KeyPolicy) -> KeyPolicy {
+        KeyPolicy {
+            budget: self.budget.clone().or_else(|| parent.budget.clone()),
+            personas: self.personas.clone().or_else(|| parent.personas.clone()),
+            requests_per_minute: self.requests_per_minute.or(parent.requests_per_minute),
+            max_tokens: self.max_tokens.or(parent.max_tokens),
+            audit_bodies: self.audit_bodies.or(parent.audit_bodies),
+        }
+    }
+
+    pub fn allows_persona(&self, persona: &str) -> bool {
+        self.personas
+            .as_ref()
+            .is_none_or(|personas| personas.iter().any(|p| p == persona))
+    }
+
+    /// `max_tokens` of a completion, within the cap.
+    pub fn max_tokens(&self, requested: Option<u32>) -> Option<u32> {
+        match (requested, self.max_tokens) {
+            (Some(requested), Some(cap)) => Some(requested.min(cap)),
+            (requested, cap) => requested.or(cap),
+        }
+    }
+}
+
+/// When, where from and by which web pages an API key may be used, checked
+/// before the request is handled.
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct KeyRestrictions {
+    /// Rejected from then on, with the `expired` error code.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Rejected until then, with the `not_yet_valid` error code.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub not_before: Option<DateTime<Utc>>,
+    /// Client addresses or CIDR ranges the key may be used from, any when
+    /// unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_ips: Option<Vec<String>>,
+    /// `Origin`s of the web pages that may use the key, e.g.
+    /// `https://app.example.com`. Requests without the header are not
+    /// browser requests and are not checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+impl KeyRestrictions {
+    /// These restrictions, with the ones left unset taken from `other`.
+    pub fn inherit(&self, other: &KeyRestrictions) -> KeyRestrictions {
+        KeyRestrictions {
+            expires_at: self.expires_at.or(other.expires_at),
+            not_before: self.not_before.or(other.not_before),
+            allowed_ips: self
+                .allowed_ips
+                .clone()
+                .or_else(|| other.allowed_ips.clone()),
+            allowed_origins: self
+                .allowed_origins
+                .clone()
+                .or_else(|| other.allowed_origins.clone()),
+        }
+    }
+
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        match (&self.allowed_ips, ip) {
+            (None, _) => true,
+            (Some(ranges), Some(ip)) => ranges
+                .iter()
+                .filter_map(|range| parse_ip_range(range).ok())
+                .any(|range| range.contains(&ip)),
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let origin = origin.trim_end_matches('/');
+        self.allowed_origins.as_ref().is_none_or(|origins| {
+            origins
+                .iter()
+                .any(|o| o.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        })
+    }
+}
+
+fn is_origin(origin: &str) -> bool {
+    origin.starts_with("https://") || origin.starts_with("http://")
+}
+
+/// An address or CIDR range, a single address is a range of its own.
+pub fn parse_ip_range(range: &str) -> Result<IpNet, String> {
+    let range = range.trim();
+    range
+        .parse::<IpNet>()
+        .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address or CIDR range `{}`", range))
+}
+
+/// A TOML datetime, or an RFC 3339 string as sent to the admin API. Dates
+/// and times without an offset are UTC.
+fn deserialize_datetime<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Toml(toml::value::Datetime),
+        Text(String),
+    }
+    let text = match Option::<Raw>::deserialize(deserializer)? {
+        Some(Raw::Toml(datetime)) => datetime.to_string(),
+        Some(Raw::Text(text)) => text,
+        None => return Ok(None),
+    };
+    DateTime::parse_from_rfc3339(&text)
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%S%.f").map(|d| d.and_utc())
+        })
+        .or_else(|_| {
+            NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        })
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("invalid datetime `{}`", text)))
+}
+
+/// `[jwt]`, bearer JWTs signed by an identity provider. Their claims are
+/// mapped to the permissions of an API key named after the subject; the
+/// rate limit, usage and threads are those of the subject.
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct JwtConfig {
+    /// Expected `iss` claim.
+    pub issuer: String,
+    /// Accepted `aud` claims, at least one.
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// JWKS with the signing keys, a file or an `https://` URL.
+    pub jwks: String,
+    /// Seconds the JWKS is cached, 300 when unset. An unknown `kid` fetches
+    /// it again sooner.
+    pub cache_ttl: Option<u64>,
+    /// Seconds of clock skew allowed on `exp` and `nbf`, 60 when unset.
+    pub leeway: Option<u64>,
+    /// Claim identifying the caller, `sub` when unset.
+    pub subject_claim: Option<String>,
+    /// Claim listing the groups of the caller, `groups` when unset.
+    pub groups_claim: Option<String>,
+    /// Claim with the space separated scopes, `scope` when unset.
+    pub scope_claim: Option<String>,
+    /// Permissions of every valid token.
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    /// Permissions by group.
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Permissions by scope.
+    #[serde(default)]
+    pub scopes: HashMap<String, Vec<String>>,
+    /// Projects of the callers, as `projects` of an API key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub projects: Vec<String>,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+}
+
+impl JwtConfig {
+    pub fn jwks_is_url(&self) -> bool {
+        self.jwks.starts_with("https://")
+    }
+
+    pub fn cache_ttl(&self) -> u64 {
+        self.cache_ttl.unwrap_or(300)
+    }
+
+    pub fn leeway(&self) -> u64 {
+        self.leeway.unwrap_or(60)
+    }
+
+    pub fn subject_claim(&self) -> &str {
+        self.subject_claim.as_deref().unwrap_or("sub")
+    }
+
+    pub fn groups_claim(&self) -> &str {
+        self.groups_claim.as_deref().unwrap_or("groups")
+    }
+
+    pub fn scope_claim(&self) -> &str {
+        self.scope_claim.as_deref().unwrap_or("scope")
+    }
+}
+
+/// A tenant, its settings apply to the keys of all its projects.
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct Organization {
+    /// Matched against the `OpenAI-Organization` header, e.g. `org-acme`.
+    pub id: String,
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+    #[serde(default)]
+    pub projects: Vec<Project>,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct Project {
+    /// Matched against the `OpenAI-Project` header, unique across
+    /// organizations.
+    pub id: String,
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+}
+
+/// Spending limit of an API key, project or organization, from the costs of
+/// the `[[models]]` prices.
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct Budget {
+    /// USD per calendar month (UTC).
+    pub monthly: f64,
+    #[serde(default)]
+    pub action: BudgetAction,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, Default, PartialEq, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    /// Reject requests once the budget is spent.
+    #[default]
+    #[display(fmt = "block")]
+    Block,
+    /// Serve them, with a warning in the log and the response headers.
+    #[display(fmt = "warn")]
+    Warn,
+}
+
+/// Permission giving access to the `/admin` API.
+pub const ADMIN_PERMISSION: &str = "admin";
+
+impl ApiKey {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+llm_backend = "openai"
+llm_api_url = "https://api.openai.com/v1"
+llm_model_name = "gpt-3.5-turbo"
+
+[[api_keys]]
+key = "nsk-base"
+name = "Base"
+permissions = ["openai:api"]
+
+[context]
+keep_last = 4
+"#;
+
+    /// A config dir named after the test, with `base.conf` and the given
+    /// `conf.d` fragments. Returns the path of `base.conf`.
+    fn config_dir(name: &str, fragments: &[(&str, &str)]) -> String {
+        let dir = env::temp_dir().join(format!("restoai-config-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(CONF_DIR)).unwrap();
+        for (file, text) in fragments {
+            fs::write(dir.join(CONF_DIR).join(file), text).unwrap();
+        }
+        let path = dir.join("base.conf");
+        fs::write(&path, BASE).unwrap();
+        path.display().to_string()
+    }
+
+    fn layered(layers: Vec<Layer>) -> Config {
+        let mut merged = toml::Value::Table(toml::Table::new());
+        for layer in layers {
+            let append = layer.text.is_some();
+            merge(&mut merged, layer.value, append);
+        }
+        merged.try_into().unwrap()
+    }
+
+    #[test]
+    fn fragments_are_merged_in_name_order() {
+        let path = config_dir(
+            "order",
+            &[
+                (
+                    "20-team.conf",
+                    "[[api_keys]]\nkey = \"nsk-b\"\nname = \"B\"\npermissions = []\n\n[context]\nkeep_last = 8\n",
+                ),
+                (
+                    "10-team.toml",
+                    "[[api_keys]]\nkey = \"nsk-a\"\nname = \"A\"\npermissions = []\n\n[context]\nkeep_last = 6\nreserve_tokens = 100\n",
+                ),
+                ("notes.txt", "not = [a config"),
+            ],
+        );
+
+        let config = load(&path).unwrap();
+        let names: Vec<&str> = config.api_keys.iter().map(|k| k.name.as_str()).collect();
+        assert_eq!(names, ["Base", "A", "B"]);
+        // tables merge key by key, the last file winning
+        assert_eq!(config.context.keep_last, Some(8));
+        assert_eq!(config.context.reserve_tokens(), 100);
+        assert_eq!(config.llm_model_name, "gpt-3.5-turbo");
+        assert_eq!(fragments(&path).len(), 2);
+    }
+
+    #[test]
+    fn fragment_errors_name_the_fragment() {
+        let path = config_dir(
+            "errors",
+            &[("10-bad.conf", "[context]\nkeep_last = \"many\"\n")],
+        );
+
+        let errors = load(&path).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].contains("10-bad.conf:2:"),
+            "unexpected error: {}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn fragments_may_leave_out_required_keys() {
+        let path = config_dir(
+            "required",
+            &[(
+                "10-keys.conf",
+                "[[api_keys]]\nkey = \"nsk-c\"\nname = \"C\"\npermissions = []\n",
+            )],
+        );
+        assert_eq!(load(&path).unwrap().api_keys.len(), 2);
+    }
+
+    #[test]
+    fn env_overrides_set_nested_values() {
+        let base = Layer::file("base.conf".to_string(), BASE.to_string()).unwrap();
+        let config = layered(vec![
+            base,
+            Layer::env("RESTOAI_LLM_MODEL_NAME", "gpt-4o"),
+            Layer::env("RESTOAI_AUDIT__ENABLED", "true"),
+            Layer::env("RESTOAI_CONTEXT__KEEP_LAST", "2"),
+            Layer::env("RESTOAI_BACKENDS__OPENAI__MODEL", "gpt-4o-mini"),
+        ]);
+
+        assert_eq!(config.llm_model_name, "gpt-4o");
+        assert!(config.audit.enabled);
+        assert_eq!(config.context.keep_last, Some(2));
+        assert_eq!(config.backend("openai").model, "gpt-4o-mini");
+    }
+
+    #[test]
+    fn env_values_are_toml_literals_or_strings() {
+        let value = |raw: &str| {
+            Layer::env("RESTOAI_X", raw)
+                .value
+                .get("x")
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(value("8192"), toml::Value::Integer(8192));
+        assert_eq!(value("false"), toml::Value::Boolean(false));
+        assert_eq!(
+            value(r#"["a", "b"]"#),
+            toml::Value::Array(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(value("gpt-4o"), toml::Value::String("gpt-4o".to_string()));
+    }
+
+    #[test]
+    fn env_overrides_replace_arrays() {
+        let base = Layer::file("base.conf".to_string(), BASE.to_string()).unwrap();
+        let config = layered(vec![
+            base,
+            Layer::env("RESTOAI_REDACTION__RULES", r#"["email"]"#),
+            Layer::env("RESTOAI_REDACTION__RULES", r#"["phone"]"#),
+        ]);
+        assert_eq!(config.redaction.rules(), vec![RedactionKind::Phone]);
+    }
+
+    #[test]
+    fn env_type_errors_are_blamed_on_the_variable() {
+        let base = Layer::file("base.conf".to_string(), BASE.to_string()).unwrap();
+        let layers = vec![base, Layer::env("RESTOAI_CONTEXT__KEEP_LAST", "lots")];
+        let mut merged = toml::Value::Table(toml::Table::new());
+        for layer in &layers {
+            merge(&mut merged, layer.value.clone(), layer.text.is_some());
+        }
+        let error = merged.try_into::<Config>().unwrap_err();
+        assert_eq!(
+            blame_env(&layers, error.message()),
+            Some("RESTOAI_CONTEXT__KEEP_LAST")
+        );
+    }
+
+    #[test]
+    fn ip_ranges_are_addresses_or_cidr() {
+        let range = parse_ip_range(" 10.0.0.0/8 ").unwrap();
+        assert!(range.contains(&"10.1.2.3".parse::<IpAddr>().unwrap()));
+        assert!(!range.contains(&"11.0.0.1".parse::<IpAddr>().unwrap()));
+
+        let single = parse_ip_range("203.0.113.7").unwrap();
+        assert!(single.contains(&"203.0.113.7".parse::<IpAddr>().unwrap()));
+        assert!(!single.contains(&"203.0.113.8".parse::<IpAddr>().unwrap()));
+
+        let v6 = parse_ip_range("2001:db8::/32").unwrap();
+        assert!(v6.contains(&"2001:db8::1".parse::<IpAddr>().unwrap()));
+
+        assert!(parse_ip_range("10.0.0.0/33").is_err());
+        assert!(parse_ip_range("example.com").is_err());
+    }
+
+    #[test]
+    fn allowed_ips_need_a_known_address() {
+        let restrictions = KeyRestrictions {
+            allowed_ips: Some(vec!["10.0.0.0/8".into(), "not a range".into()]),
+            ..Default::default()
+        };
+        assert!(restrictions.allows_ip(Some("10.0.0.1".parse().unwrap())));
+        assert!(!restrictions.allows_ip(Some("192.168.0.1".parse().unwrap())));
+        assert!(!restrictions.allows_ip(None));
+        assert!(KeyRestrictions::default().allows_ip(None));
+    }
+
+    #[test]
+    fn restriction_dates_without_offset_are_utc() {
+        let key: ApiKey = toml::from_str(
+            "key = \"k\"\nname = \"n\"\npermissions = []\n\
+             expires_at = 2024-12-31\nnot_before = \"2024-07-01T08:00:00+02:00\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            key.restrictions.expires_at.unwrap().to_rfc3339(),
+            "2024-12-31T00:00:00+00:00"
+        );
+        assert_eq!(
+            key.restrictions.not_before.unwrap().to_rfc3339(),
+            "2024-07-01T06:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn jwks_is_fetched_over_https_only() {
+        let jwt = |jwks: &str, audience: &str| -> Config {
+            toml::from_str(&format!(
+                "{}\n[jwt]\nissuer = \"https://login.example.com/\"\n\
+                 audience = [{}]\njwks = \"{}\"\n",
+                BASE, audience, jwks
+            ))
+            .unwrap()
+        };
+        let issues = |config: &Config| -> Vec<String> {
+            config
+                .validate()
+                .into_iter()
+                .filter(|i| i.path.as_deref().unwrap_or_default().starts_with("jwt"))
+                .map(|i| format!("{}: {}", i.path.unwrap_or_default(), i.message))
+                .collect()
+        };
+
+        let https = jwt("https://login.example.com/jwks.json", "\"restoai\"");
+        assert!(https.jwt.as_ref().unwrap().jwks_is_url());
+        assert!(issues(&https).is_empty());
+
+        let http = jwt("http://login.example.com/jwks.json", "\"restoai\"");
+        assert!(!http.jwt.as_ref().unwrap().jwks_is_url());
+        let http_issues = issues(&http);
+        assert_eq!(http_issues.len(), 1);
+        assert!(http_issues[0].starts_with("jwt.jwks: the JWKS must be fetched over https"));
+
+        let anyone = jwt("https://login.example.com/jwks.json", "");
+        assert_eq!(issues(&anyone), ["jwt.audience: missing audience"]);
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Context window management.
+//!
+//! Fits an assembled prompt into the upstream model context window before it
+//! is submitted, using the strategy configured in the `[context]` section.
+
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent, Role};
+
+use crate::{
+    config::{ContextConfig, TruncationStrategy},
+    error::ApiError,
+    llm::LlmBackend,
+    tokenizer::{message_text, Tokenizer},
+};
+
+const DEFAULT_KEEP_LAST: usize = 4;
+
+const SUMMARIZE_PROMPT: &str = "Summarize the following conversation in a few sentences. \
+    Keep names, decisions, code identifiers and open questions.";
+
+/// What was done to make the prompt fit, reported back to the client.
+#[derive(Debug, Clone)]
+pub struct Truncation {
+    pub strategy: TruncationStrategy,
+    /// Number of client messages removed or folded into a summary.
+    pub dropped: usize,
+    /// Prompt size after truncation.
+    pub prompt_tokens: u32,
+}
+
+impl Truncation {
+    pub fn header_value(&self) -> String {
+        format!(
+            "{}; dropped={}; prompt_tokens={}",
+            self.strategy, self.dropped, self.prompt_tokens
+        )
+    }
+}
+
+/// Index of the first non-system message; system messages at the head of the
+/// prompt are never truncated.
+fn history_start(messages: &[ChatMessage]) -> usize {
+    messages
+        .iter()
+        .position(|m| m.role != Role::System)
+        .unwrap_or(messages.len())
+}
+
+/// Drops the oldest history messages until the prompt fits in `limit`. The
+/// latest message is always kept.
+fn drop_oldest(messages: &mut Vec<ChatMessage>, tokenizer: &Tokenizer, limit: u32) -> usize {
+    let start = history_start(messages);
+    let mut total = tokenizer.count_messages(messages);
+    let mut dropped = 0;
+    while total > limit && messages.len() > start + 1 {
+        total -= tokenizer.count_message(&messages.remove(start));
+        dropped += 1;
+    }
+    dropped
+}
+
+/// Keeps the system prompt and the last `keep_last` messages only.
+fn keep_last(messages: &mut Vec<ChatMessage>, keep_last: usize) -> usize {
+    let start = history_start(messages);
+    let history = messages.len() - start;
+    let keep_last = keep_last.max(1);
+    if history <= keep_last {
+        return 0;
+    }
+    messages.drain(start..messages.len() - keep_last);
+    history - keep_last
+}
+
+/// Folds everything but the last `keep_last` messages into a summary written
+/// by the configured (usually cheaper) summarizer model.
+async fn summarize<T: LlmBackend>(
+    backend: &T,
+    messages: &mut Vec<ChatMessage>,
+    keep_last: usize,
+    summarize_model: &str,
+    request_id: Option<&str>,
+) -> Result<usize, ApiError> {
+    let start = history_start(messages);
+    let keep_last = keep_last.max(1);
+    if messages.len() - start <= keep_last {
+        return Ok(0);
+    }
+    let older: Vec<ChatMessage> = messages.drain(start..messages.len() - keep_last).collect();
+
+    let transcript = older
+        .iter()
+        .map(|m| format!("{}: {}", m.role, message_text(m)))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let summary = backend
+        .complete(
+            vec![
+                ChatMessage {
+                    role: Role::System,
+                    content: ChatMessageContent::Text(SUMMARIZE_PROMPT.to_string()),
+                    ..Default::default()
+                },
+                ChatMessage {
+                    role: Role::User,
+                    content: ChatMessageContent::Text(transcript),
+                    ..Default::default()
+                },
+            ],
+            summarize_model,
+            request_id,
+        )
+        .await?;
+
+    messages.insert(
+        start,
+        ChatMessage {
+            role: Role::System,
+            content: ChatMessageContent::Text(format!(
+                "Summary of the earlier conversation: {}",
+                summary
+            )),
+            ..Default::default()
+        },
+    );
+
+    Ok(older.len())
+}
+
+/// Applies the configured truncation strategy when `messages` exceed
+/// `context_window` minus the tokens reserved for the completion.
+pub async fn fit_context<T: LlmBackend>(
+    backend: &T,
+    messages: &mut Vec<ChatMessage>,
+    tokenizer: &Tokenizer,
+    context_window: u32,
+    config: &ContextConfig,
+    request_id: Option<&str>,
+) -> Result<Option<Truncation>, ApiError> {
+    let limit = context_window.saturating_sub(config.reserve_tokens());
+    if tokenizer.count_messages(messages) <= limit {
+        return Ok(None);
+    }
+
+    let keep = config.keep_last.unwrap_or(DEFAULT_KEEP_LAST);
+    let mut dropped = match config.strategy {
+        TruncationStrategy::DropOldest => 0,
+        TruncationStrategy::KeepLast => keep_last(messages, keep),
+        TruncationStrategy::Summarize => match &config.summarize_model {
+            Some(summarize_model) => {
+                summarize(backend, messages, keep, summarize_model, request_id).await?
+            }
+            None => {
+                warn!("context strategy is `summarize` but no summarize_model configured");
+                0
+            }
+        },
+    };
+    // whatever the strategy, never submit more than the model accepts
+    dropped += drop_oldest(messages, tokenizer, limit);
+
+    let prompt_tokens = tokenizer.count_messages(messages);
+    if prompt_tokens > limit {
+        return Err(ApiError::BadRequest(format!(
+            "Prompt is {} tokens, exceeding the {} tokens available in the context window",
+            prompt_tokens, limit
+        )));
+    }
+
+    debug!(
+        "context truncated with {} strategy: dropped {} messages, {} tokens left",
+        config.strategy, dropped, prompt_tokens
+    );
+
+    Ok(Some(Truncation {
+        strategy: config.strategy.clone(),
+        dropped,
+        prompt_tokens,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apitype,
+        config::Config,
+        llm::{Prompt, PromptContext},
+        streamer::StreamWriter,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Backend answering every completion with the same summary.
+    struct Summarizer {
+        tokenizer: Tokenizer,
+        /// Model and messages of each completion asked for.
+        calls: Mutex<Vec<(String, Vec<ChatMessage>)>>,
+    }
+
+    impl Summarizer {
+        fn new() -> Self {
+            Summarizer {
+                tokenizer: Tokenizer::Estimate,
+                calls: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    impl LlmBackend for Summarizer {
+        type MR = ();
+
+        async fn models(&self) -> Result<(), ApiError> {
+            Ok(())
+        }
+
+        fn from_config(_: &Config) -> Result<Arc<Self>, String> {
+            Ok(Arc::new(Summarizer::new()))
+        }
+
+        fn tokenizer(&self) -> &Tokenizer {
+            &self.tokenizer
+        }
+
+        fn context_window(&self) -> u32 {
+            0
+        }
+
+        fn assemble_prompt(
+            &self,
+            _: Vec<ChatMessage>,
+            _: &str,
+            _: &PromptContext,
+        ) -> Result<Prompt, ApiError> {
+            unimplemented!()
+        }
+
+        async fn build_prompt(
+            &self,
+            _: Vec<ChatMessage>,
+            _: &str,
+            _: &PromptContext,
+        ) -> Result<Prompt, ApiError> {
+            unimplemented!()
+        }
+
+        async fn submit_prompt(
+            &self,
+            _: Prompt,
+        ) -> Result<apitype::ChatCompletionResponse, ApiError> {
+            unimplemented!()
+        }
+
+        async fn submit_prompt_stream(&self, _: Prompt, _: StreamWriter) {
+            unimplemented!()
+        }
+
+        async fn complete(
+            &self,
+            chat_messages: Vec<ChatMessage>,
+            upstream_model: &str,
+            _: Option<&str>,
+        ) -> Result<String, ApiError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((upstream_model.to_string(), chat_messages));
+            Ok("They agreed on the plan.".into())
+        }
+    }
+
+    fn message(role: Role, text: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: ChatMessageContent::Text(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The system prompt and `turns` messages of 40 characters, 14 tokens
+    /// each when estimated, alternating user and assistant and ending with
+    /// the user.
+    fn conversation(turns: usize) -> Vec<ChatMessage> {
+        let mut messages = vec![message(Role::System, &"s".repeat(40))];
+        for i in 0..turns {
+            let role = match (turns - i) % 2 {
+                1 => Role::User,
+                _ => Role::Assistant,
+            };
+            messages.push(message(role, &format!("{:0>40}", i)));
+        }
+        messages
+    }
+
+    fn config(strategy: TruncationStrategy, keep_last: Option<usize>) -> ContextConfig {
+        ContextConfig {
+            strategy,
+            keep_last,
+            summarize_model: None,
+            reserve_tokens: Some(0),
+        }
+    }
+
+    async fn fit(
+        messages: &mut Vec<ChatMessage>,
+        context_window: u32,
+        config: &ContextConfig,
+    ) -> Result<Option<Truncation>, ApiError> {
+        fit_context(
+            &Summarizer::new(),
+            messages,
+            &Tokenizer::Estimate,
+            context_window,
+            config,
+            None,
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn prompts_that_fit_are_left_alone() {
+        let mut messages = conversation(4);
+        let config = config(TruncationStrategy::DropOldest, None);
+        assert!(fit(&mut messages, 70, &config).await.unwrap().is_none());
+        assert_eq!(messages, conversation(4));
+    }
+
+    #[actix_web::test]
+    async fn drop_oldest_keeps_the_system_prompt_and_the_newest_messages() {
+        let original = conversation(6);
+        let mut messages = original.clone();
+        let config = config(TruncationStrategy::DropOldest, None);
+        let truncation = fit(&mut messages, 60, &config).await.unwrap().unwrap();
+
+        assert_eq!(truncation.dropped, 3);
+        assert_eq!(messages.len(), original.len() - truncation.dropped);
+        assert_eq!(messages[0], original[0]);
+        assert_eq!(messages[1..], original[4..]);
+        assert!(Tokenizer::Estimate.count_messages(&messages) <= 60);
+        assert_eq!(
+            truncation.header_value(),
+            "drop_oldest; dropped=3; prompt_tokens=56"
+        );
+    }
+
+    #[actix_web::test]
+    async fn reserved_tokens_are_left_for_the_completion() {
+        let mut messages = conversation(6);
+        let config = ContextConfig {
+            reserve_tokens: Some(20),
+            ..config(TruncationStrategy::DropOldest, None)
+        };
+        let truncation = fit(&mut messages, 80, &config).await.unwrap().unwrap();
+        assert_eq!(truncation.dropped, 3);
+        assert!(truncation.prompt_tokens <= 60);
+    }
+
+    #[actix_web::test]
+    async fn keep_last_keeps_the_last_messages_only() {
+        let original = conversation(6);
+        let mut messages = original.clone();
+        let config = config(TruncationStrategy::KeepLast, Some(2));
+        let truncation = fit(&mut messages, 90, &config).await.unwrap().unwrap();
+
+        assert_eq!(messages[0], original[0]);
+        assert_eq!(messages[1..], original[5..]);
+        assert_eq!(
+            truncation.header_value(),
+            "keep_last; dropped=4; prompt_tokens=42"
+        );
+    }
+
+    #[actix_web::test]
+    async fn keep_last_drops_more_when_the_last_messages_do_not_fit() {
+        let original = conversation(6);
+        let mut messages = original.clone();
+        let config = config(TruncationStrategy::KeepLast, Some(4));
+        let truncation = fit(&mut messages, 45, &config).await.unwrap().unwrap();
+
+        assert_eq!(truncation.dropped, 4);
+        assert_eq!(messages[0], original[0]);
+        assert_eq!(messages[1..], original[5..]);
+        assert_eq!(truncation.prompt_tokens, 42);
+    }
+
+    #[actix_web::test]
+    async fn leading_system_messages_are_never_dropped() {
+        let mut original = conversation(4);
+        original.insert(1, message(Role::System, &"t".repeat(40)));
+        let mut messages = original.clone();
+        let config = config(TruncationStrategy::DropOldest, None);
+        let truncation = fit(&mut messages, 45, &config).await.unwrap().unwrap();
+
+        assert_eq!(truncation.dropped, 3);
+        assert_eq!(messages[..2], original[..2]);
+        assert_eq!(messages[2], original[5]);
+    }
+
+    #[actix_web::test]
+    async fn a_newest_message_too_large_is_refused() {
+        let mut messages = conversation(2);
+        messages.push(message(Role::User, &"x".repeat(400)));
+        let config = config(TruncationStrategy::DropOldest, None);
+        let error = fit(&mut messages, 60, &config).await.unwrap_err();
+        assert!(matches!(error, ApiError::BadRequest(_)));
+    }
+
+    #[actix_web::test]
+    async fn summarize_folds_older_messages_into_a_summary() {
+        let original = conversation(6);
+        let mut messages = original.clone();
+        let config = ContextConfig {
+            summarize_model: Some("gpt-4o-mini".into()),
+            ..config(TruncationStrategy::Summarize, Some(2))
+        };
+        let backend = Summarizer::new();
+        let truncation = fit_context(
+            &backend,
+            &mut messages,
+            &Tokenizer::Estimate,
+            90,
+            &config,
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], original[0]);
+        assert_eq!(messages[1].role, Role::System);
+        assert_eq!(
+            message_text(&messages[1]),
+            "Summary of the earlier conversation: They agreed on the plan."
+        );
+        assert_eq!(messages[2..], original[5..]);
+        assert_eq!(truncation.dropped, 4);
+        assert_eq!(
+            truncation.header_value(),
+            format!(
+                "summarize; dropped=4; prompt_tokens={}",
+                Tokenizer::Estimate.count_messages(&messages)
+            )
+        );
+
+        let calls = backend.calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        let (model, prompt) = &calls[0];
+        assert_eq!(model, "gpt-4o-mini");
+        let transcript = message_text(&prompt[1]);
+        assert!(transcript.contains(message_text(&original[1])));
+        assert!(transcript.contains(message_text(&original[4])));
+        assert!(!transcript.contains(message_text(&original[5])));
+    }
+
+    #[actix_web::test]
+    async fn summarize_without_a_model_drops_the_oldest() {
+        let original = conversation(6);
+        let mut messages = original.clone();
+        let config = config(TruncationStrategy::Summarize, Some(2));
+        let truncation = fit(&mut messages, 60, &config).await.unwrap().unwrap();
+
+        assert_eq!(truncation.dropped, 3);
+        assert_eq!(messages[1..], original[4..]);
+    }
+}
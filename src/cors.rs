@@ -0,0 +1,148 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! CORS for browser clients, from `[cors]` of the current config.
+//!
+//! Preflight requests are answered here, before any authentication, since
+//! browsers send them without the `Authorization` header. Other requests
+//! from an allowed origin get their CORS headers added to the response,
+//! error responses included, so pages can read why a request failed. The
+//! `allowed_origins` of an API key are checked with the key.
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{
+        header::{
+            HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        Method,
+    },
+    web, Error, HttpResponse, ResponseError,
+};
+use futures::future::{ready, LocalBoxFuture};
+
+use crate::{
+    appctx::AppContext, config::CorsConfig, error::ApiError, llm::OpenAiBackend,
+    logging::REQUEST_ID_HEADER,
+};
+
+/// Response headers of the server, readable by pages without configuring
+/// them, like `x-request-id`.
+const RESTOAI_HEADER_PREFIX: &str = "x-restoai-";
+
+fn header_value(value: &str) -> Option<HeaderValue> {
+    HeaderValue::from_str(value).ok()
+}
+
+/// The answer to a preflight from an allowed origin, or the refusal.
+fn preflight(cors: &CorsConfig, origin: &str, headers: &HeaderMap) -> HttpResponse {
+    if !cors.allows_origin(origin) {
+        return ApiError::OriginNotAllowed(format!("Origin '{}' is not allowed", origin))
+            .error_response();
+    }
+    let methods = cors.allowed_methods();
+    let method = headers
+        .get(ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !methods.iter().any(|m| m == method) {
+        return ApiError::Forbidden(format!("Method '{}' is not allowed", method)).error_response();
+    }
+
+    let mut response = HttpResponse::NoContent();
+    if let Some(origin) = header_value(origin) {
+        response.insert_header((ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+    }
+    if let Some(methods) = header_value(&methods.join(", ")) {
+        response.insert_header((ACCESS_CONTROL_ALLOW_METHODS, methods));
+    }
+    let allowed_headers = match &cors.allowed_headers {
+        Some(allowed) => header_value(&allowed.join(", ")),
+        None => headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+    };
+    if let Some(allowed_headers) = allowed_headers {
+        response.insert_header((ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers));
+    }
+    response.insert_header((ACCESS_CONTROL_MAX_AGE, cors.max_age()));
+    response.insert_header((VARY, "Origin"));
+    response.finish()
+}
+
+/// Adds the CORS headers of an allowed origin to a response.
+fn allow(cors: &CorsConfig, origin: &str, headers: &mut HeaderMap) {
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+    let origin = match header_value(origin).filter(|_| cors.allows_origin(origin)) {
+        Some(origin) => origin,
+        None => return,
+    };
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+
+    let mut exposed: Vec<&str> = headers
+        .keys()
+        .map(HeaderName::as_str)
+        .filter(|name| name.starts_with(RESTOAI_HEADER_PREFIX))
+        .collect();
+    // set outside this middleware, on every response
+    exposed.push(REQUEST_ID_HEADER);
+    exposed.extend(cors.expose_headers.iter().map(String::as_str));
+    exposed.sort_unstable();
+    exposed.dedup();
+    if let Some(exposed) = header_value(&exposed.join(", ")) {
+        headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+    }
+}
+
+/// Middleware answering preflights and adding CORS headers to the other
+/// responses, when `[cors]` is set and the request has an `Origin`.
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let cors = req
+        .app_data::<web::Data<AppContext<OpenAiBackend>>>()
+        .and_then(|ctx| ctx.config().cors.clone());
+    let origin = req
+        .headers()
+        .get(ORIGIN)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let (cors, origin) = match (cors, origin) {
+        (Some(cors), Some(origin)) => (cors, origin),
+        _ => {
+            let response = srv.call(req);
+            return Box::pin(async move { response.await.map(|res| res.map_into_boxed_body()) });
+        }
+    };
+
+    if req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let response = preflight(&cors, &origin, req.headers());
+        return Box::pin(ready(Ok(req.into_response(response))));
+    }
+
+    // authentication and handler errors arrive here as responses already
+    let response = srv.call(req);
+    Box::pin(async move {
+        let mut res = response.await?.map_into_boxed_body();
+        allow(&cors, &origin, res.headers_mut());
+        Ok(res)
+    })
+}
@@ -5,7 +5,6 @@ use actix_web::{
     web::{self, Json},
     HttpRequest, HttpResponse, Responder,
 };
-use actix_web_httpauth::extractors::bearer::BearerAuth;
 use derive_more::{Deref, DerefMut, From};
 use either::Either;
 use futures::{Stream, StreamExt, TryStream};
@@ -15,42 +14,52 @@ use openai_dive::v1::resources::{
     shared::FinishReason,
 };
 use parking_lot::Mutex;
+use pickledb::PickleDb;
 use serde_derive::{self, Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 use tokio::sync::mpsc;
+use tracing::{Instrument, Span};
 
 use std::borrow::Cow;
 
 use crate::{
     apitype,
     appctx::AppContext,
-    llm::{LlmBackend, OpenAiBackend},
-    streamer::StreamWriter,
+    auth::Caller,
+    config::Config,
+    error::ApiError,
+    llm::{LlmBackend, OpenAiBackend, PromptContext},
+    logging::RequestId,
+    streamer::{self, StreamWriter},
+    tenant::Tenant,
+    tokenizer::message_text,
+    usage,
 };
 
 type OAIAppContext = AppContext<OpenAiBackend>;
 
-lazy_static! {
-    static ref AVAILABLE_MODELS: Vec<&'static str> = vec!["programmer", "sysadmin",];
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct HitCounter {
+    /// Id of the caller, see [`Caller::id`]. Named `token` as it used to be
+    /// the token of the API key.
     pub token: String,
     pub hits: u32,
 }
 
-pub fn track_metric_counter(path: &str, token: &str, ctx: &OAIAppContext) {
+pub fn track_metric_counter(path: &str, caller_id: &str, ctx: &OAIAppContext) {
     let db = ctx.db.clone();
     let mut db = db.lock().unwrap();
     let mut hits: HashMap<String, u32> = HashMap::new();
     if let Some(val) = db.get::<Vec<HitCounter>>(path) {
         let mut exists = false;
         val.iter().for_each(|v| {
-            if v.token == token {
+            if v.token == caller_id {
                 let cnt = v.hits + 1;
-                hits.insert(token.to_string(), cnt);
+                hits.insert(caller_id.to_string(), cnt);
 
                 debug!("{} - {} hits", path, cnt);
                 exists = true;
@@ -59,10 +68,10 @@ pub fn track_metric_counter(path: &str, token: &str, ctx: &OAIAppContext) {
             }
         });
         if !exists {
-            hits.insert(token.to_string(), 1);
+            hits.insert(caller_id.to_string(), 1);
         }
     } else {
-        hits.insert(token.to_string(), 1);
+        hits.insert(caller_id.to_string(), 1);
     }
 
     let hits_data: Vec<HitCounter> = hits
@@ -76,25 +85,105 @@ pub fn track_metric_counter(path: &str, token: &str, ctx: &OAIAppContext) {
     db.set(path, &json!(hits_data)).unwrap();
 }
 
-fn is_model_supported(model: &str) -> bool {
-    AVAILABLE_MODELS.contains(&model)
+/// Moves the hits counted under the token of an API key, as they were before
+/// callers were known by the key name, to that name.
+pub fn migrate_counters(db: &mut PickleDb, config: &Config) {
+    let paths = db
+        .get_all()
+        .into_iter()
+        .filter(|path| path.starts_with('/'));
+    for path in paths.collect::<Vec<_>>() {
+        let counters = db.get::<Vec<HitCounter>>(&path).unwrap_or_default();
+        if !counters.iter().any(|c| config.api_key(&c.token).is_some()) {
+            continue;
+        }
+
+        let mut hits: BTreeMap<String, u32> = BTreeMap::new();
+        for counter in counters {
+            let id = match config.api_key(&counter.token) {
+                Some(key) => key.name.clone(),
+                None => counter.token,
+            };
+            *hits.entry(id).or_default() += counter.hits;
+        }
+        let counters: Vec<HitCounter> = hits
+            .into_iter()
+            .map(|(token, hits)| HitCounter { token, hits })
+            .collect();
+        if let Err(e) = db.set(&path, &counters) {
+            error!("hits of {} not migrated: {}", path, e);
+        }
+    }
+}
+
+/// Hits per endpoint and caller, as `(path, caller id, hits)`.
+pub fn metric_counters(ctx: &OAIAppContext) -> Vec<(String, String, u32)> {
+    let db = ctx.db.lock().unwrap();
+    db.get_all()
+        .into_iter()
+        .filter(|path| path.starts_with('/'))
+        .flat_map(|path| {
+            db.get::<Vec<HitCounter>>(&path)
+                .unwrap_or_default()
+                .into_iter()
+                .map(move |counter| (path.clone(), counter.token, counter.hits))
+        })
+        .collect()
 }
 
-#[post("/chat/completions")]
-pub async fn chat_completions(
-    data: web::Json<apitype::ChatCompletionParameters>,
-    ctx: web::Data<OAIAppContext>,
-    credential: BearerAuth,
-) -> impl Responder {
-    if !is_model_supported(&data.model) {
-        return HttpResponse::BadRequest().body("Model not supported");
+pub fn is_model_supported(model: &str, ctx: &OAIAppContext) -> bool {
+    ctx.config().persona(model).is_some()
+}
+
+const VAR_HEADER_PREFIX: &str = "x-restoai-var-";
+
+/// Template variables for the persona prompt: request `metadata`, overridden
+/// by `x-restoai-var-*` headers (`x-restoai-var-team-name` sets `team_name`).
+pub fn prompt_context(
+    req: &HttpRequest,
+    caller: &Caller,
+    user: Option<String>,
+    metadata: Option<&HashMap<String, String>>,
+) -> PromptContext {
+    let mut vars = metadata.cloned().unwrap_or_default();
+    for (name, value) in req.headers() {
+        if let (Some(var), Ok(value)) = (
+            name.as_str().strip_prefix(VAR_HEADER_PREFIX),
+            value.to_str(),
+        ) {
+            vars.insert(var.replace('-', "_"), value.to_string());
+        }
+    }
+
+    PromptContext {
+        key_name: Some(caller.key.name.clone()),
+        user,
+        vars,
+        language: caller.key.language.clone(),
+        tenant: Default::default(),
+        request_id: RequestId::of(req),
     }
+}
 
-    // log metric for the current credential
-    track_metric_counter("/chat/completions", credential.token(), &ctx);
+/// Applies the policy the key gets from its project and organization to a
+/// completion request: allowed personas, rate limit and budget. Returns the
+/// tenant, with the budget warning if any.
+pub fn admit(
+    req: &HttpRequest,
+    ctx: &OAIAppContext,
+    caller: &Caller,
+    persona: &str,
+) -> Result<(Tenant, Option<String>), ApiError> {
+    let tenant = Tenant::resolve(req, &ctx.config(), &caller.key)?;
+    tenant.check_persona(persona)?;
+    ctx.limiter
+        .check(&caller.id, tenant.policy.requests_per_minute)?;
+    let budget_warning = usage::check_budget(ctx, &caller.id, &caller.key.name, &tenant)?;
+    Ok((tenant, budget_warning))
+}
 
-    let messages = data
-        .messages
+fn to_chat_messages(messages: &[apitype::ChatMessage]) -> Vec<ChatMessage> {
+    messages
         .iter()
         .map(|m| ChatMessage {
             role: m.role.clone(),
@@ -102,42 +191,123 @@ pub async fn chat_completions(
             name: m.name.clone(),
             ..Default::default()
         })
-        .collect();
+        .collect()
+}
 
-    if data.stream == Some(true) {
-        let (tx, mut rx) = mpsc::channel(10);
-        let writer = StreamWriter(Arc::new(tx));
+#[post("/chat/completions")]
+pub async fn chat_completions(
+    req: HttpRequest,
+    data: web::Json<apitype::ChatCompletionParameters>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, ApiError> {
+    if !is_model_supported(&data.model, &ctx) {
+        return Err(ApiError::BadRequest("Model not supported".into()));
+    }
+    Span::current().record("persona", data.model.as_str());
 
-        let llm_backend = ctx.llm_backend.clone();
+    let (tenant, budget_warning) = admit(&req, &ctx, &caller, &data.model)?;
 
-        tokio::spawn(async move {
-            llm_backend
-                .submit_prompt_stream(messages, writer, &data.model)
-                .await;
-        });
+    // log metric for the current caller
+    track_metric_counter("/chat/completions", &caller.id, &ctx);
 
-        HttpResponse::build(StatusCode::OK)
-            .insert_header(("Content-Type", "text/event-stream"))
-            .insert_header(("Cache-Control", "no-cache"))
-            .streaming(Box::pin(async_stream::stream! {
-                while let Some(event) = rx.recv().await {
-                    debug!("++Event: {}", event);
-                    yield Ok::<_,actix_web::error::Error>(web::Bytes::from(["data: ", &event, "\n\n"].concat()));
-                }
+    let messages = to_chat_messages(&data.messages);
+    let mut prompt_ctx = prompt_context(&req, &caller, data.user.clone(), data.metadata.as_ref());
+    prompt_ctx.tenant = tenant;
 
-                // send [DONE] message
-                yield Ok::<_,actix_web::error::Error>(web::Bytes::from("data: [DONE]\n\n"));
+    let stream = data.stream == Some(true);
+    let mut audit = ctx
+        .audit
+        .start(req.path(), &prompt_ctx, &data.model, stream);
 
-                trace!("[*] STREAM CLOSED.");
-            }))
+    // the same backend builds and submits the prompt, even across a reload
+    let llm_backend = ctx.llm_backend();
+    let mut prompt = match llm_backend
+        .build_prompt(messages, &data.model, &prompt_ctx)
+        .await
+    {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            audit.error(&e);
+            usage::record(&ctx, &caller.id, &mut audit);
+            ctx.audit.write(&audit);
+            return Err(e);
+        }
+    };
+    // sampling is the caller's choice, the persona only shapes the prompt
+    prompt.parameters.temperature = data.temperature;
+    prompt.parameters.max_tokens = prompt_ctx.tenant.policy.max_tokens(data.max_tokens);
+    Span::current().record("upstream", prompt.parameters.model.as_str());
+    audit.prompt(&prompt, llm_backend.tokenizer());
+
+    let mut response = HttpResponse::Ok();
+    prompt.insert_headers(&mut response);
+    if let Some(warning) = budget_warning {
+        response.insert_header(("x-restoai-budget-warning", warning));
+    }
+
+    if stream {
+        let (backend_tx, backend_rx) = mpsc::channel(10);
+        let (tx, rx) = mpsc::channel(10);
+
+        let tokenizer = llm_backend.tokenizer().clone();
+
+        tokio::spawn(
+            async move {
+                llm_backend
+                    .submit_prompt_stream(prompt, StreamWriter(Arc::new(backend_tx)))
+                    .await;
+            }
+            .in_current_span(),
+        );
+
+        // audit the answer assembled from the relayed chunks
+        let active = ctx
+            .streams
+            .register(req.path(), prompt_ctx.key_name.clone(), &data.model);
+        let ctx = ctx.clone();
+        let caller_id = caller.id.clone();
+        tokio::spawn(
+            async move {
+                let relayed = streamer::relay(backend_rx, StreamWriter(Arc::new(tx))).await;
+                drop(active);
+                audit.stream(&relayed, &tokenizer);
+                usage::record(&ctx, &caller_id, &mut audit);
+                ctx.audit.write(&audit);
+            }
+            .in_current_span(),
+        );
+
+        Ok(streamer::event_stream(response, rx))
     } else {
-        HttpResponse::Ok().json(ctx.llm_backend.submit_prompt(messages, &data.model).await)
+        let result = match llm_backend.submit_prompt(prompt).await {
+            Ok(result) => result,
+            Err(e) => {
+                audit.error(&e);
+                usage::record(&ctx, &caller.id, &mut audit);
+                ctx.audit.write(&audit);
+                return Err(e);
+            }
+        };
+        audit.response(&result);
+        // streams are priced once relayed, after their headers are sent
+        if let Some(cost) = usage::record(&ctx, &caller.id, &mut audit) {
+            response.insert_header(("x-restoai-cost", format!("{:.6}", cost)));
+        }
+        ctx.audit.write(&audit);
+        Ok(response.json(result))
     }
 }
 
 #[get("/models")]
-pub async fn models(_ctx: web::Data<OAIAppContext>) -> impl Responder {
-    //let models = ctx.llm_backend.models().await;
+pub async fn models(
+    req: HttpRequest,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, ApiError> {
+    let config = ctx.config();
+    let tenant = Tenant::resolve(&req, &config, &caller.key)?;
+    //let models = ctx.llm_backend().models().await;
 
     let models = apitype::ListModelResponse {
         //object: models.object,
@@ -152,10 +322,12 @@ pub async fn models(_ctx: web::Data<OAIAppContext>) -> impl Responder {
         //     })
         //     .collect(),
         object: "list".into(),
-        data: AVAILABLE_MODELS
+        data: config
+            .personas()
             .iter()
+            .filter(|m| tenant.policy.allows_persona(&m.name))
             .map(|m| apitype::Model {
-                id: (*m).into(),
+                id: m.name.clone(),
                 object: "model".into(),
                 created: 0,
                 owned_by: Some("organization-owner".into()),
@@ -163,5 +335,74 @@ pub async fn models(_ctx: web::Data<OAIAppContext>) -> impl Responder {
             .collect(),
     };
 
-    HttpResponse::Ok().json(models)
+    Ok(HttpResponse::Ok().json(models))
+}
+
+#[post("/tokenize")]
+pub async fn tokenize(
+    req: HttpRequest,
+    data: web::Json<apitype::ChatCompletionParameters>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, ApiError> {
+    if !is_model_supported(&data.model, &ctx) {
+        return Err(ApiError::BadRequest("Model not supported".into()));
+    }
+    // a persona the key may not use is not counted either
+    let (tenant, _) = admit(&req, &ctx, &caller, &data.model)?;
+
+    let mut prompt_ctx = prompt_context(&req, &caller, data.user.clone(), data.metadata.as_ref());
+    prompt_ctx.tenant = tenant;
+    let llm_backend = ctx.llm_backend();
+    let messages = llm_backend
+        .assemble_prompt(to_chat_messages(&data.messages), &data.model, &prompt_ctx)?
+        .parameters
+        .messages;
+    let tokenizer = llm_backend.tokenizer();
+
+    Ok(HttpResponse::Ok().json(apitype::TokenizeResponse {
+        object: "tokenize".into(),
+        model: data.model.clone(),
+        tokenizer: tokenizer.name().to_string(),
+        messages: messages
+            .iter()
+            .map(|m| apitype::MessageTokens {
+                role: m.role.clone(),
+                count: tokenizer.count_message(m),
+                tokens: (m.role != Role::System)
+                    .then(|| tokenizer.encode(message_text(m)))
+                    .flatten(),
+            })
+            .collect(),
+        prompt_tokens: tokenizer.count_messages(&messages),
+    }))
+}
+
+#[post("/chat/completions/count_tokens")]
+pub async fn count_tokens(
+    req: HttpRequest,
+    data: web::Json<apitype::ChatCompletionParameters>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, ApiError> {
+    if !is_model_supported(&data.model, &ctx) {
+        return Err(ApiError::BadRequest("Model not supported".into()));
+    }
+    // a persona the key may not use is not counted either
+    let (tenant, _) = admit(&req, &ctx, &caller, &data.model)?;
+
+    let mut prompt_ctx = prompt_context(&req, &caller, data.user.clone(), data.metadata.as_ref());
+    prompt_ctx.tenant = tenant;
+    let llm_backend = ctx.llm_backend();
+    let messages = llm_backend
+        .assemble_prompt(to_chat_messages(&data.messages), &data.model, &prompt_ctx)?
+        .parameters
+        .messages;
+
+    Ok(HttpResponse::Ok().json(apitype::TokenCount {
+        object: "token_count".into(),
+        model: data.model.clone(),
+        prompt_tokens: llm_backend.tokenizer().count_messages(&messages),
+        context_window: llm_backend.context_window(),
+    }))
 }
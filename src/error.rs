@@ -0,0 +1,96 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use derive_more::Display;
+use serde_json::json;
+
+/// Errors returned to API clients, rendered as OpenAI-style error bodies.
+#[derive(Debug, Display)]
+pub enum ApiError {
+    #[display(fmt = "{}", _0)]
+    BadRequest(String),
+    #[display(fmt = "{}", _0)]
+    Forbidden(String),
+    #[display(fmt = "{}", _0)]
+    KeyExpired(String),
+    #[display(fmt = "{}", _0)]
+    KeyNotYetValid(String),
+    #[display(fmt = "{}", _0)]
+    IpNotAllowed(String),
+    #[display(fmt = "{}", _0)]
+    OriginNotAllowed(String),
+    #[display(fmt = "{}", _0)]
+    PersonaNotAllowed(String),
+    #[display(fmt = "{}", _0)]
+    NotFound(String),
+    #[display(fmt = "{}", _0)]
+    RateLimited(String),
+    #[display(fmt = "{}", _0)]
+    QuotaExceeded(String),
+    #[display(fmt = "{}", _0)]
+    Upstream(String),
+    #[display(fmt = "{}", _0)]
+    Internal(String),
+}
+
+impl ApiError {
+    fn error_type(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_)
+            | ApiError::Forbidden(_)
+            | ApiError::KeyExpired(_)
+            | ApiError::KeyNotYetValid(_)
+            | ApiError::IpNotAllowed(_)
+            | ApiError::OriginNotAllowed(_)
+            | ApiError::PersonaNotAllowed(_)
+            | ApiError::NotFound(_) => "invalid_request_error",
+            ApiError::RateLimited(_) => "rate_limit_exceeded",
+            ApiError::QuotaExceeded(_) => "insufficient_quota",
+            ApiError::Upstream(_) => "upstream_error",
+            ApiError::Internal(_) => "server_error",
+        }
+    }
+
+    /// Tells apart the refusals of a restricted API key.
+    fn code(&self) -> Option<&'static str> {
+        match self {
+            ApiError::KeyExpired(_) => Some("expired"),
+            ApiError::KeyNotYetValid(_) => Some("not_yet_valid"),
+            ApiError::IpNotAllowed(_) => Some("ip_not_allowed"),
+            ApiError::OriginNotAllowed(_) => Some("origin_not_allowed"),
+            ApiError::PersonaNotAllowed(_) => Some("persona_not_allowed"),
+            _ => None,
+        }
+    }
+
+    /// The error body, also sent as the last event of a failed stream.
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "message": self.to_string(),
+                "type": self.error_type(),
+                "param": null,
+                "code": self.code(),
+            }
+        })
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::KeyExpired(_) | ApiError::KeyNotYetValid(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_)
+            | ApiError::IpNotAllowed(_)
+            | ApiError::OriginNotAllowed(_)
+            | ApiError::PersonaNotAllowed(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::RateLimited(_) | ApiError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.body())
+    }
+}
@@ -0,0 +1,22 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Guardrails applied to prompts before they are forwarded upstream, and to
+//! the model output before it is returned.
+
+mod injection;
+mod moderate;
+mod redact;
+
+pub use injection::InjectionDetector;
+pub use moderate::{Moderator, OutputFilter};
+pub use redact::{Redactions, Redactor, Restorer};
@@ -0,0 +1,275 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Output moderation.
+//!
+//! Masks or blocks configured terms and patterns in the model output. Streamed
+//! output is held back by `max_match_length` bytes so that a match split over
+//! several chunks is still caught.
+
+use regex::Regex;
+
+use crate::config::{ModerationAction, ModerationConfig};
+
+const DEFAULT_MASK: &str = "[filtered]";
+const DEFAULT_MAX_MATCH_LENGTH: usize = 128;
+
+pub struct Moderator {
+    /// All terms and patterns as one alternation, `None` when nothing is
+    /// configured.
+    regex: Option<Regex>,
+    action: ModerationAction,
+    mask: String,
+    max_match_length: usize,
+}
+
+/// Moderated, complete model output.
+pub struct Moderated {
+    pub text: String,
+    pub flagged: bool,
+}
+
+impl Moderator {
+    pub fn from_config(config: &ModerationConfig) -> Result<Self, String> {
+        let alternatives: Vec<String> = config
+            .terms
+            .iter()
+            .map(|term| format!(r"(?i:\b{}\b)", regex::escape(term)))
+            .chain(config.patterns.iter().map(|p| format!("(?:{})", p)))
+            .collect();
+
+        let regex = (!alternatives.is_empty())
+            .then(|| Regex::new(&alternatives.join("|")))
+            .transpose()
+            .map_err(|e| format!("Invalid moderation pattern: {}", e))?;
+
+        Ok(Self {
+            regex,
+            action: config.action.clone(),
+            mask: config
+                .mask
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MASK.to_string()),
+            max_match_length: config.max_match_length.unwrap_or(DEFAULT_MAX_MATCH_LENGTH),
+        })
+    }
+
+    pub fn action(&self) -> &ModerationAction {
+        &self.action
+    }
+
+    pub fn moderate(&self, text: &str) -> Moderated {
+        let mut filter = self.filter();
+        let mut moderated = filter.push(text);
+        moderated.push_str(&filter.finish());
+        Moderated {
+            text: moderated,
+            flagged: filter.is_flagged(),
+        }
+    }
+
+    /// Moderates streamed text, see [`OutputFilter`].
+    pub fn filter(&self) -> OutputFilter<'_> {
+        OutputFilter {
+            moderator: self,
+            pending: String::new(),
+            flagged: false,
+        }
+    }
+}
+
+/// Moderates a stream of text chunks, holding back the tail that may still
+/// turn into a match.
+pub struct OutputFilter<'a> {
+    moderator: &'a Moderator,
+    pending: String,
+    flagged: bool,
+}
+
+impl OutputFilter<'_> {
+    /// Whether a term or pattern was found so far.
+    pub fn is_flagged(&self) -> bool {
+        self.flagged
+    }
+
+    /// Whether the output was cut off, nothing is returned after that.
+    pub fn is_blocked(&self) -> bool {
+        self.flagged && self.moderator.action == ModerationAction::Block
+    }
+
+    pub fn push(&mut self, text: &str) -> String {
+        if self.is_blocked() {
+            return String::new();
+        }
+        self.pending.push_str(text);
+
+        // a match ending in text not received yet starts within the last
+        // `max_match_length` bytes
+        let mut safe = self
+            .pending
+            .len()
+            .saturating_sub(self.moderator.max_match_length);
+        while !self.pending.is_char_boundary(safe) {
+            safe -= 1;
+        }
+        self.release(safe)
+    }
+
+    /// Remaining text at the end of the stream.
+    pub fn finish(&mut self) -> String {
+        if self.is_blocked() {
+            return String::new();
+        }
+        self.release(self.pending.len())
+    }
+
+    /// Moderates and returns the pending text up to `end`, or up to the start
+    /// of a match crossing `end`.
+    fn release(&mut self, mut end: usize) -> String {
+        let regex = match &self.moderator.regex {
+            Some(regex) => regex,
+            None => return self.pending.drain(..).collect(),
+        };
+
+        let mut released = String::new();
+        let mut last = 0;
+        for m in regex.find_iter(&self.pending) {
+            if m.start() >= end {
+                break;
+            }
+            if m.end() > end {
+                end = m.start();
+                break;
+            }
+
+            self.flagged = true;
+            released.push_str(&self.pending[last..m.start()]);
+            last = m.end();
+            match self.moderator.action {
+                ModerationAction::Mask => released.push_str(&self.moderator.mask),
+                ModerationAction::Block => {
+                    self.pending.clear();
+                    return released;
+                }
+            }
+        }
+
+        released.push_str(&self.pending[last..end]);
+        self.pending.drain(..end);
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderator(action: ModerationAction, max_match_length: usize) -> Moderator {
+        Moderator::from_config(&ModerationConfig {
+            action,
+            terms: vec!["secret".to_string()],
+            patterns: vec![r"sk-[a-z0-9]{8}".to_string()],
+            max_match_length: Some(max_match_length),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Pushes the chunks one by one, then finishes.
+    fn stream(filter: &mut OutputFilter, chunks: &[&str]) -> String {
+        let mut output: String = chunks.iter().map(|chunk| filter.push(chunk)).collect();
+        output.push_str(&filter.finish());
+        output
+    }
+
+    #[test]
+    fn masks_terms_and_patterns() {
+        let moderated = moderator(ModerationAction::Mask, 16)
+            .moderate("The Secret key is sk-abcd1234, secretly.");
+        assert!(moderated.flagged);
+        assert_eq!(
+            moderated.text,
+            "The [filtered] key is [filtered], secretly."
+        );
+    }
+
+    #[test]
+    fn passes_clean_text() {
+        let moderated = moderator(ModerationAction::Mask, 16).moderate("Nothing to see here.");
+        assert!(!moderated.flagged);
+        assert_eq!(moderated.text, "Nothing to see here.");
+    }
+
+    #[test]
+    fn holds_back_the_tail() {
+        let moderator = moderator(ModerationAction::Mask, 4);
+        let mut filter = moderator.filter();
+        assert_eq!(filter.push("hello world"), "hello w");
+        assert_eq!(filter.push(""), "");
+        assert_eq!(filter.finish(), "orld");
+    }
+
+    #[test]
+    fn holds_back_at_char_boundaries() {
+        let moderator = moderator(ModerationAction::Mask, 3);
+        let mut filter = moderator.filter();
+        // the last 3 bytes start within the 2-byte `é`
+        assert_eq!(filter.push("abcé!"), "abc");
+        assert_eq!(filter.finish(), "é!");
+    }
+
+    #[test]
+    fn catches_matches_across_chunks() {
+        let moderator = moderator(ModerationAction::Mask, 16);
+        let mut filter = moderator.filter();
+        let output = stream(&mut filter, &["my sk-ab", "cd12", "34 and a se", "cret"]);
+        assert!(filter.is_flagged());
+        assert_eq!(output, "my [filtered] and a [filtered]");
+    }
+
+    #[test]
+    fn finish_releases_a_match_at_the_end() {
+        let moderator = moderator(ModerationAction::Mask, 16);
+        let mut filter = moderator.filter();
+        assert_eq!(filter.push("a secret"), "");
+        assert!(!filter.is_flagged());
+        assert_eq!(filter.finish(), "a [filtered]");
+        assert!(filter.is_flagged());
+    }
+
+    #[test]
+    fn block_cuts_off_at_the_first_match() {
+        let moderator = moderator(ModerationAction::Block, 16);
+        let mut filter = moderator.filter();
+        let output = stream(&mut filter, &["keep this, se", "cret and", " the rest"]);
+        assert!(filter.is_blocked());
+        assert_eq!(output, "keep this, ");
+        assert_eq!(filter.push("more"), "");
+    }
+
+    #[test]
+    fn mask_never_blocks() {
+        let moderator = moderator(ModerationAction::Mask, 16);
+        let mut filter = moderator.filter();
+        stream(&mut filter, &["secret"]);
+        assert!(filter.is_flagged());
+        assert!(!filter.is_blocked());
+    }
+
+    #[test]
+    fn nothing_configured_passes_everything() {
+        let moderator = Moderator::from_config(&ModerationConfig::default()).unwrap();
+        let mut filter = moderator.filter();
+        assert_eq!(filter.push("secret"), "secret");
+        assert_eq!(filter.finish(), "");
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! PII redaction.
+//!
+//! Replaces emails, phone numbers, NIK, credit card numbers and API secrets in
+//! the prompt with placeholders like `[EMAIL_1]` before it leaves the server,
+//! and optionally puts the original values back into the response.
+
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent};
+use regex::Regex;
+use std::{collections::BTreeMap, fmt};
+
+use crate::config::{RedactionConfig, RedactionKind};
+
+lazy_static! {
+    static ref EMAIL: Regex = Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap();
+    static ref SECRET: Regex = Regex::new(
+        r"\b(?:n?sk|pk|rk)-[A-Za-z0-9_-]{16,}|\bAKIA[0-9A-Z]{16}\b|\bgh[pousr]_[A-Za-z0-9]{36}\b|\bxox[abpr]-[A-Za-z0-9-]{10,}"
+    )
+    .unwrap();
+    // ASCII digits only, `\d` also matches the digits of other scripts
+    static ref CREDIT_CARD: Regex = Regex::new(r"\b[0-9](?:[ -]?[0-9]){12,18}\b").unwrap();
+    static ref NIK: Regex = Regex::new(r"\b[0-9]{16}\b").unwrap();
+    static ref PHONE: Regex =
+        Regex::new(r"(?:\+[0-9]{1,3}[\s-]?[0-9]{2,4}|\b0[0-9]{2,3})(?:[\s-]?[0-9]{3,4}){2,3}\b")
+            .unwrap();
+}
+
+impl RedactionKind {
+    fn regex(&self) -> &'static Regex {
+        match self {
+            RedactionKind::Email => &EMAIL,
+            RedactionKind::Secret => &SECRET,
+            RedactionKind::CreditCard => &CREDIT_CARD,
+            RedactionKind::Nik => &NIK,
+            RedactionKind::Phone => &PHONE,
+        }
+    }
+
+    /// Checksum or structure validation on top of the regex match.
+    fn is_valid(&self, value: &str) -> bool {
+        match self {
+            RedactionKind::CreditCard => luhn(value),
+            RedactionKind::Nik => valid_nik(value),
+            _ => true,
+        }
+    }
+
+    fn placeholder(&self) -> &'static str {
+        match self {
+            RedactionKind::Email => "EMAIL",
+            RedactionKind::Secret => "SECRET",
+            RedactionKind::CreditCard => "CARD",
+            RedactionKind::Nik => "NIK",
+            RedactionKind::Phone => "PHONE",
+        }
+    }
+}
+
+/// Luhn checksum of the digits in `value`.
+fn luhn(value: &str) -> bool {
+    let digits: Vec<u32> = value.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match (i % 2, d * 2) {
+            (1, d) if d > 9 => d - 9,
+            (1, d) => d,
+            _ => *d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Indonesian NIK: province code, city, district, then the birth date as
+/// DDMMYY (day + 40 for women) and a serial number.
+fn valid_nik(value: &str) -> bool {
+    let digits: Vec<u32> = match value.chars().map(|c| c.to_digit(10)).collect() {
+        Some(digits) => digits,
+        None => return false,
+    };
+    if digits.len() != 16 {
+        return false;
+    }
+    let num = |range: std::ops::Range<usize>| digits[range].iter().fold(0, |n, d| n * 10 + d);
+    let (province, day, month) = (num(0..2), num(6..8), num(8..10));
+    (11..=94).contains(&province)
+        && ((1..=31).contains(&day) || (41..=71).contains(&day))
+        && (1..=12).contains(&month)
+        && num(12..16) > 0
+}
+
+/// The values redacted from a prompt.
+#[derive(Debug, Default, Clone)]
+pub struct Redactions {
+    /// Placeholder and original value.
+    entries: Vec<(String, String)>,
+    counts: BTreeMap<RedactionKind, usize>,
+    restore: bool,
+}
+
+impl Redactions {
+    pub fn count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn placeholder_for(&mut self, kind: RedactionKind, value: &str) -> String {
+        if let Some((placeholder, _)) = self.entries.iter().find(|(_, v)| v == value) {
+            return placeholder.clone();
+        }
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        let placeholder = format!("[{}_{}]", kind.placeholder(), count);
+        self.entries.push((placeholder.clone(), value.to_string()));
+        placeholder
+    }
+
+    /// Puts the original values back, unless restoring is disabled.
+    pub fn restore(&self, text: &str) -> String {
+        if !self.restore {
+            return text.to_string();
+        }
+        self.entries
+            .iter()
+            .fold(text.to_string(), |text, (placeholder, value)| {
+                text.replace(placeholder, value)
+            })
+    }
+
+    /// Restores placeholders in streamed text, see [`Restorer`].
+    pub fn restorer(&self) -> Restorer<'_> {
+        Restorer {
+            redactions: self,
+            pending: String::new(),
+        }
+    }
+}
+
+/// Per kind counts, e.g. `email=2, phone=1`.
+impl fmt::Display for Redactions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(kind, count)| format!("{}={}", kind, count))
+            .collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+/// Restores placeholders in a stream of text chunks. A placeholder split over
+/// two chunks is held back until it is complete.
+pub struct Restorer<'a> {
+    redactions: &'a Redactions,
+    pending: String,
+}
+
+impl Restorer<'_> {
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+
+        // hold back a trailing `[...` that may still become a placeholder
+        let hold = self.pending.rfind('[').filter(|&i| {
+            let tail = &self.pending[i..];
+            !tail.contains(']')
+                && self
+                    .redactions
+                    .entries
+                    .iter()
+                    .any(|(placeholder, _)| placeholder.starts_with(tail))
+        });
+
+        let ready: String = match hold {
+            Some(i) => self.pending.drain(..i).collect(),
+            None => self.pending.drain(..).collect(),
+        };
+        self.redactions.restore(&ready)
+    }
+
+    /// Remaining text at the end of the stream.
+    pub fn finish(&mut self) -> String {
+        let rest: String = self.pending.drain(..).collect();
+        self.redactions.restore(&rest)
+    }
+}
+
+pub struct Redactor {
+    rules: Vec<RedactionKind>,
+    restore: bool,
+}
+
+impl Redactor {
+    pub fn from_config(config: &RedactionConfig) -> Self {
+        Self {
+            rules: config.rules(),
+            restore: config.restore,
+        }
+    }
+
+    fn redact(&self, text: &str, redactions: &mut Redactions) -> String {
+        self.rules.iter().fold(text.to_string(), |text, kind| {
+            kind.regex()
+                .replace_all(&text, |caps: &regex::Captures| {
+                    let value = &caps[0];
+                    if kind.is_valid(value) {
+                        redactions.placeholder_for(*kind, value)
+                    } else {
+                        value.to_string()
+                    }
+                })
+                .into_owned()
+        })
+    }
+
+    /// Redacts the text content of every message in place.
+    pub fn redact_messages(&self, messages: &mut [ChatMessage]) -> Redactions {
+        let mut redactions = Redactions {
+            restore: self.restore,
+            ..Default::default()
+        };
+        for message in messages.iter_mut() {
+            if let ChatMessageContent::Text(text) = &message.content {
+                let redacted = self.redact(text, &mut redactions);
+                message.content = ChatMessageContent::Text(redacted);
+            }
+        }
+        redactions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(restore: bool) -> Redactor {
+        Redactor::from_config(&RedactionConfig {
+            restore,
+            ..Default::default()
+        })
+    }
+
+    fn redact(text: &str) -> (String, Redactions) {
+        let mut redactions = Redactions {
+            restore: true,
+            ..Default::default()
+        };
+        let redacted = redactor(true).redact(text, &mut redactions);
+        (redacted, redactions)
+    }
+
+    #[test]
+    fn luhn_checks_the_digits() {
+        assert!(luhn("4111 1111 1111 1111"));
+        assert!(luhn("4111-1111-1111-1111"));
+        assert!(!luhn("4111 1111 1111 1112"));
+        // too short for a card
+        assert!(!luhn("4111 1111 111"));
+    }
+
+    #[test]
+    fn nik_checks_region_and_birth_date() {
+        assert!(valid_nik("3273011508900001"));
+        // women have 40 added to the day
+        assert!(valid_nik("3273015508900001"));
+        // no province 99, month 13 or serial 0
+        assert!(!valid_nik("9973011508900001"));
+        assert!(!valid_nik("3273011513900001"));
+        assert!(!valid_nik("3273011508900000"));
+        assert!(!valid_nik("327301150890000"));
+    }
+
+    #[test]
+    fn non_ascii_digits_are_not_redacted() {
+        let devanagari = "३२७३०११५०८९००००१";
+        assert!(!valid_nik(devanagari));
+        assert!(!luhn(devanagari));
+        for kind in redactor(true).rules {
+            assert!(!kind.regex().is_match(devanagari), "{}", kind);
+        }
+
+        let text = format!("my NIK is {}", devanagari);
+        let (redacted, redactions) = redact(&text);
+        assert_eq!(redacted, text);
+        assert!(redactions.is_empty());
+    }
+
+    #[test]
+    fn redacts_each_kind() {
+        let (redacted, redactions) = redact(
+            "mail ani@example.co.id, call +62 812-3456-7890, NIK 3273011508900001, \
+             card 4111 1111 1111 1111, key sk-abcdefghijklmnopqrstuv",
+        );
+        assert_eq!(
+            redacted,
+            "mail [EMAIL_1], call [PHONE_1], NIK [NIK_1], card [CARD_1], key [SECRET_1]"
+        );
+        assert_eq!(
+            redactions.to_string(),
+            "email=1, phone=1, nik=1, credit_card=1, secret=1"
+        );
+    }
+
+    #[test]
+    fn keeps_numbers_failing_validation() {
+        let (redacted, redactions) = redact("order 4111 1111 1111 1112, id 9973011508900001");
+        assert_eq!(redacted, "order 4111 1111 1111 1112, id 9973011508900001");
+        assert!(redactions.is_empty());
+    }
+
+    #[test]
+    fn repeated_values_share_a_placeholder() {
+        let (redacted, redactions) = redact("ani@example.com, budi@example.com, ani@example.com");
+        assert_eq!(redacted, "[EMAIL_1], [EMAIL_2], [EMAIL_1]");
+        assert_eq!(redactions.count(), 2);
+    }
+
+    #[test]
+    fn restores_values_unless_disabled() {
+        let (redacted, mut redactions) = redact("write to ani@example.com");
+        assert_eq!(redactions.restore(&redacted), "write to ani@example.com");
+
+        redactions.restore = false;
+        assert_eq!(redactions.restore(&redacted), redacted);
+    }
+
+    #[test]
+    fn restorer_holds_back_split_placeholders() {
+        let (_, redactions) = redact("ani@example.com");
+        let mut restorer = redactions.restorer();
+        assert_eq!(restorer.push("Hello [EM"), "Hello ");
+        assert_eq!(restorer.push("AIL_"), "");
+        assert_eq!(restorer.push("1], bye"), "ani@example.com, bye");
+        // brackets that cannot become a placeholder are not held
+        assert_eq!(restorer.push(" [note]"), " [note]");
+        assert_eq!(restorer.push(" [x"), " [x");
+        assert_eq!(restorer.finish(), "");
+    }
+
+    #[test]
+    fn finish_flushes_an_incomplete_placeholder() {
+        let (_, redactions) = redact("ani@example.com");
+        let mut restorer = redactions.restorer();
+        assert_eq!(restorer.push("see [EMAIL"), "see ");
+        assert_eq!(restorer.finish(), "[EMAIL");
+    }
+}
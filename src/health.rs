@@ -0,0 +1,124 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Health checks for load balancers and orchestrators.
+//!
+//! `/healthz` only tells the process is alive. `/readyz` checks that the last
+//! config reload succeeded, the app database and upstream, and answers 503
+//! when any of them fails.
+
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use parking_lot::Mutex;
+use serde_json::json;
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    appctx::{AppContext, DB_PATH},
+    llm::{LlmBackend, OpenAiBackend},
+    thread,
+};
+
+type OAIAppContext = AppContext<OpenAiBackend>;
+
+/// Upstream is probed at most this often, whatever the readiness poll rate.
+const PROBE_TTL: Duration = Duration::from_secs(30);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn result<E: ToString>(result: Result<(), E>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Last upstream probe, shared by the workers.
+#[derive(Default)]
+pub struct UpstreamProbe {
+    last: Mutex<Option<(Instant, Check)>>,
+}
+
+impl UpstreamProbe {
+    async fn check<T: LlmBackend>(&self, backend: &T) -> Check {
+        if let Some((at, check)) = &*self.last.lock() {
+            if at.elapsed() < PROBE_TTL {
+                return check.clone();
+            }
+        }
+
+        let check = match actix_web::rt::time::timeout(PROBE_TIMEOUT, backend.models()).await {
+            Ok(result) => Check::result(result.map(|_| ())),
+            Err(_) => Check::result(Err("timed out")),
+        };
+        if let Some(e) = &check.error {
+            warn!("upstream probe failed: {}", e);
+        }
+        *self.last.lock() = Some((Instant::now(), check.clone()));
+        check
+    }
+}
+
+/// The database is usable and its directory writable. Not a write to the
+/// database itself, which would dump all of it on every probe; each probe
+/// writes a file of its own, so overlapping ones do not remove each other's.
+fn check_storage(ctx: &OAIAppContext) -> Result<(), String> {
+    drop(ctx.db.lock().map_err(|_| "database lock poisoned")?);
+    let probe = format!("{}.{}", DB_PATH, thread::generate_id("readyz"));
+    fs::write(&probe, b"ok")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("cannot write next to {}: {}", DB_PATH, e))
+}
+
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+pub async fn readyz(
+    ctx: web::Data<OAIAppContext>,
+    probe: web::Data<UpstreamProbe>,
+) -> impl Responder {
+    // the previous config is still in use, but not the one on disk
+    let config = Check::result(match ctx.reload_error() {
+        Some(e) => Err(format!("last reload failed: {}", e)),
+        None => Ok(()),
+    });
+
+    let storage = Check::result(check_storage(&ctx));
+
+    let upstream = probe.check(&*ctx.llm_backend()).await;
+
+    let ready = config.ok && storage.ok && upstream.ok;
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    HttpResponse::build(status).json(json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": {
+            "config": config,
+            "storage": storage,
+            "upstream": upstream,
+        },
+    }))
+}
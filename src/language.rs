@@ -0,0 +1,57 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Response language selection.
+
+use openai_dive::v1::resources::chat::{ChatMessage, Role};
+use whatlang::Lang;
+
+use crate::{config::LanguagePolicy, tokenizer::message_text};
+
+/// English name of an ISO 639-3 code (`ind` -> `Indonesian`), other values
+/// are used as is.
+fn language_name(language: &str) -> String {
+    Lang::from_code(language.to_lowercase())
+        .map(|lang| lang.eng_name().to_string())
+        .unwrap_or_else(|| language.to_string())
+}
+
+/// Language of the last user message, if it can be reliably detected.
+fn detect_user_language(messages: &[ChatMessage]) -> Option<String> {
+    let text = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == Role::User)
+        .map(message_text)?;
+
+    let info = whatlang::detect(text)?;
+    trace!(
+        "detected language {} with confidence {}",
+        info.lang(),
+        info.confidence()
+    );
+    info.is_reliable()
+        .then(|| info.lang().eng_name().to_string())
+}
+
+/// The language to answer in, `None` when the model should decide.
+pub fn resolve(policy: &LanguagePolicy, messages: &[ChatMessage]) -> Option<String> {
+    match policy {
+        LanguagePolicy::Fixed(language) => Some(language_name(language)),
+        LanguagePolicy::MatchUser => detect_user_language(messages),
+    }
+}
+
+/// Sentence appended to the system prompt.
+pub fn instruction(language: &str) -> String {
+    format!("Always respond in {}.", language)
+}
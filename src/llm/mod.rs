@@ -1,30 +1,120 @@
-use std::{io::Write, sync::Arc};
+use actix_web::{http::header::HeaderValue, HttpResponseBuilder};
+use std::{collections::HashMap, io::Write, sync::Arc};
 
-use crate::{apitype, config::Config, streamer::StreamWriter};
+use crate::{
+    apitype,
+    config::{Config, LanguagePolicy},
+    context::Truncation,
+    error::ApiError,
+    guardrail::Redactions,
+    streamer::StreamWriter,
+    tenant::Tenant,
+    tokenizer::Tokenizer,
+};
 
 mod openai;
 
 pub use openai::OpenAiBackend;
 
-use openai_dive::v1::resources::chat::ChatMessage;
+use openai_dive::v1::resources::chat::{ChatCompletionParameters, ChatMessage};
+
+/// Per-request values available to persona prompt templates.
+#[derive(Debug, Default, Clone)]
+pub struct PromptContext {
+    /// Name of the API key making the request.
+    pub key_name: Option<String>,
+    /// The `user` field of the request.
+    pub user: Option<String>,
+    /// Variables from the request `metadata` and `x-restoai-var-*` headers.
+    pub vars: HashMap<String, String>,
+    /// Language policy of the API key, overrides the persona one.
+    pub language: Option<LanguagePolicy>,
+    /// Organization and project the request is made for.
+    pub tenant: Tenant,
+    /// Id of the request, sent with every upstream call made for it.
+    pub request_id: Option<String>,
+}
+
+/// An upstream request assembled by [`LlmBackend::build_prompt`], ready to be submitted.
+#[derive(Debug)]
+pub struct Prompt {
+    pub parameters: ChatCompletionParameters,
+    /// The persona requested by the client.
+    pub model: String,
+    pub truncation: Option<Truncation>,
+    /// Language the model was instructed to answer in.
+    pub language: Option<String>,
+    /// Values replaced by placeholders before submitting.
+    pub redactions: Redactions,
+    pub request_id: Option<String>,
+}
+
+impl Prompt {
+    /// Reports how the prompt was assembled through `x-restoai-*` response headers.
+    pub fn insert_headers(&self, response: &mut HttpResponseBuilder) {
+        if let Some(truncation) = &self.truncation {
+            response.insert_header(("x-restoai-context-truncation", truncation.header_value()));
+        }
+        // configured languages are free text, skip values not allowed in headers
+        if let Some(language) = self
+            .language
+            .as_deref()
+            .and_then(|l| HeaderValue::from_str(l).ok())
+        {
+            response.insert_header(("x-restoai-language", language));
+        }
+        if !self.redactions.is_empty() {
+            response.insert_header(("x-restoai-redactions", self.redactions.count()));
+        }
+    }
+}
 
 pub trait LlmBackend {
     type MR;
 
-    async fn models(&self) -> Self::MR;
+    /// Models listed by upstream, also a reachability probe.
+    async fn models(&self) -> Result<Self::MR, ApiError>;
 
-    fn from_config(config: &Config) -> Arc<Self>;
+    /// Fails on settings the config validation cannot check, e.g. a
+    /// tokenizer file that does not load.
+    fn from_config(config: &Config) -> Result<Arc<Self>, String>;
 
-    async fn submit_prompt(
+    /// Tokenizer of the upstream model.
+    fn tokenizer(&self) -> &Tokenizer;
+
+    fn context_window(&self) -> u32;
+
+    /// Persona system prompt followed by the client messages, neither
+    /// truncated nor submitted.
+    fn assemble_prompt(
         &self,
         chat_messages: Vec<ChatMessage>,
         model: &str,
-    ) -> apitype::ChatCompletionResponse;
+        prompt_ctx: &PromptContext,
+    ) -> Result<Prompt, ApiError>;
 
-    async fn submit_prompt_stream(
+    /// Assembles the prompt and fits it into the upstream context window.
+    async fn build_prompt(
         &self,
         chat_messages: Vec<ChatMessage>,
-        stream_writer: StreamWriter,
         model: &str,
-    );
+        prompt_ctx: &PromptContext,
+    ) -> Result<Prompt, ApiError>;
+
+    async fn submit_prompt(
+        &self,
+        prompt: Prompt,
+    ) -> Result<apitype::ChatCompletionResponse, ApiError>;
+
+    /// Writes the chunks of the answer to `stream_writer`, ending with an
+    /// error event when upstream fails.
+    async fn submit_prompt_stream(&self, prompt: Prompt, stream_writer: StreamWriter);
+
+    /// Plain completion against an upstream model, without any persona.
+    async fn complete(
+        &self,
+        chat_messages: Vec<ChatMessage>,
+        upstream_model: &str,
+        request_id: Option<&str>,
+    ) -> Result<String, ApiError>;
 }
@@ -1,135 +1,511 @@
 use actix_web_lab::body::writer;
 use futures::StreamExt;
+use minijinja::{AutoEscape, Environment, UndefinedBehavior};
 use openai_dive::v1::{
     api::Client,
     resources::chat::{
-        ChatCompletionChunkResponse, ChatCompletionParameters, ChatMessage, ChatMessageContent,
-        Role,
+        ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionResponse, ChatMessage,
+        ChatMessageContent, Role,
     },
+    resources::model::ListModelResponse,
+    resources::shared::FinishReason,
+};
+use reqwest_eventsource::RequestBuilderExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    io::Write,
+    sync::Arc,
 };
-use std::{env, io::Write, sync::Arc};
 
-use crate::config::Config;
-use crate::llm::LlmBackend;
+use crate::config::{
+    Config, ContextConfig, InjectionPolicy, Persona, RedactionConfig, SystemPromptPolicy,
+    UpstreamBackend,
+};
+use crate::context;
+use crate::error::ApiError;
+use crate::guardrail::{InjectionDetector, Moderator, OutputFilter, Redactor, Restorer};
+use crate::language;
+use crate::llm::{LlmBackend, Prompt, PromptContext};
+use crate::logging::REQUEST_ID_HEADER;
+use crate::secret::Secret;
 use crate::streamer::StreamWriter;
+use crate::tokenizer::{message_text, Tokenizer};
 use crate::{
     apitype,
     endpoint::{self},
 };
 
+/// Id of a completion, made from the request id when upstream gives none.
+fn completion_id(id: String, request_id: Option<&str>) -> String {
+    match request_id {
+        Some(request_id) if id.is_empty() => format!("chatcmpl-{}", request_id),
+        _ => id,
+    }
+}
+
+/// Restores and moderates the streamed text of one choice.
+struct ChoiceOutput<'a> {
+    restorer: Restorer<'a>,
+    /// `None` when the persona is not moderated.
+    filter: Option<OutputFilter<'a>>,
+    /// Whether the held back text was released.
+    finished: bool,
+}
+
+impl ChoiceOutput<'_> {
+    /// Text of a delta to send, with everything held back once `finished`.
+    fn push(&mut self, text: &str, finished: bool) -> String {
+        let mut content = self.restorer.push(text);
+        if finished {
+            content.push_str(&self.restorer.finish());
+            self.finished = true;
+        }
+        if let Some(filter) = &mut self.filter {
+            content = filter.push(&content);
+            if finished {
+                content.push_str(&filter.finish());
+            }
+        }
+        content
+    }
+
+    fn is_flagged(&self) -> bool {
+        self.filter.as_ref().is_some_and(OutputFilter::is_flagged)
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.filter.as_ref().is_some_and(OutputFilter::is_blocked)
+    }
+}
+
 pub struct OpenAiBackend {
     //api_key: String,
     client: Arc<Client>,
+    /// Upstream model the personas run on.
+    model: String,
+    context_window: u32,
+    context: ContextConfig,
+    tokenizer: Tokenizer,
+    personas: Vec<Persona>,
+    /// Persona system prompts, compiled by persona name.
+    templates: Environment<'static>,
+    redaction: RedactionConfig,
+    redactor: Redactor,
+    moderation_enabled: bool,
+    moderator: Moderator,
+    /// Policy of personas without their own, `None` when disabled.
+    injection_policy: Option<InjectionPolicy>,
+    injection: InjectionDetector,
 }
 
 impl OpenAiBackend {
-    pub fn new<TStr: ToString>(api_key: Option<TStr>, base_url: &str) -> Self {
-        let api_key: String = api_key.map_or_else(
-            || {
-                env::var("LLM_BACKEND_API_KEY")
-                    .or(env::var("OPENAI_API_KEY")) // try OPENAI_API_KEY
-                    .expect("LLM_BACKEND_API_KEY nor OPENAI_API_KEY not set")
-            },
-            |d| d.to_string(),
-        );
+    pub fn new(backend: UpstreamBackend, config: &Config) -> Result<Self, String> {
+        let api_key: Secret = match backend.api_key {
+            Some(api_key) => api_key,
+            None => env::var("LLM_BACKEND_API_KEY")
+                .or(env::var("OPENAI_API_KEY")) // try OPENAI_API_KEY
+                .map(Secret::new)
+                .map_err(|_| "LLM_BACKEND_API_KEY nor OPENAI_API_KEY not set".to_string())?,
+        };
+
+        let personas = config.personas().to_vec();
+        let mut templates = Environment::new();
+        templates.set_undefined_behavior(UndefinedBehavior::Strict);
+        templates.set_auto_escape_callback(|_| AutoEscape::None);
+        for persona in &personas {
+            templates
+                .add_template_owned(persona.name.clone(), persona.system_prompt.clone())
+                .map_err(|e| format!("Invalid system prompt for `{}`: {}", persona.name, e))?;
+        }
 
-        debug!("Creating OpenAI backend with base URL: {}", base_url);
-        debug!("  with API key: {}", api_key);
+        debug!(
+            "Creating OpenAI backend with base URL: {}, model: {}",
+            backend.api_url, backend.model
+        );
 
-        OpenAiBackend {
+        Ok(OpenAiBackend {
             //api_key,
             client: Arc::new(Client {
                 http_client: reqwest::Client::new(),
-                base_url: base_url.to_string(),
-                api_key,
-                organization: None,
-                project: None,
+                base_url: backend.api_url,
+                api_key: api_key.expose().to_string(),
+                organization: backend.organization,
+                project: backend.project,
             }),
-        }
+            context_window: config.context_window(&backend.model),
+            context: config.context.clone(),
+            tokenizer: Tokenizer::from_config(config, &backend.model)?,
+            model: backend.model,
+            personas,
+            templates,
+            redaction: config.redaction.clone(),
+            redactor: Redactor::from_config(&config.redaction),
+            moderation_enabled: config.moderation.enabled,
+            moderator: Moderator::from_config(&config.moderation)?,
+            injection_policy: config.injection.enabled.then_some(config.injection.policy),
+            injection: InjectionDetector::from_config(&config.injection)?,
+        })
     }
 
-    fn system_prompt_from_model(&self, model: &str) -> ChatMessageContent {
-        if model == "programmer" {
-            ChatMessageContent::Text("You are top notch software engineer in the world, you can give recommendation and best practice in programming and will give concise \
-                and optimized code example when needed. And always response in Bahasa Indonesia.".to_string())
-        } else if model == "sysadmin" {
-            ChatMessageContent::Text("You are top notch sysadmin in the world, you can give recommendation and best practice in system administration and devops, and will give \
-                concise and optimized code example when needed. And always response in Bahasa Indonesia.".to_string())
-        } else {
-            ChatMessageContent::Text("You are a helpful assistant.".to_string())
+    /// A chat completion request upstream, carrying the id of the request
+    /// it is made for. Not through `client.chat()`, which has no way to add
+    /// the header.
+    fn chat_request(
+        &self,
+        parameters: &ChatCompletionParameters,
+        request_id: Option<&str>,
+    ) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .build_request(
+                reqwest::Method::POST,
+                "/chat/completions",
+                "application/json",
+            )
+            .json(parameters);
+        match request_id {
+            Some(request_id) => request.header(REQUEST_ID_HEADER, request_id),
+            None => request,
         }
     }
 
-    fn build_prompt(
+    fn persona(&self, model: &str) -> Option<&Persona> {
+        self.personas.iter().find(|p| p.name == model)
+    }
+
+    fn warn_filtered(&self, model: &str) {
+        warn!(
+            "content filter ({}) applied to `{}` response",
+            self.moderator.action(),
+            model
+        );
+    }
+
+    fn moderates(&self, model: &str) -> bool {
+        self.persona(model)
+            .and_then(|p| p.moderate)
+            .unwrap_or(self.moderation_enabled)
+    }
+
+    fn injection_policy(&self, model: &str) -> Option<InjectionPolicy> {
+        self.persona(model)
+            .and_then(|p| p.injection_policy)
+            .or(self.injection_policy)
+            .filter(|policy| *policy != InjectionPolicy::Off)
+    }
+
+    /// Renders the persona prompt template. Persona defaults are overridden by
+    /// request variables, built-ins override both.
+    fn render_persona_prompt(
         &self,
-        chat_messages: Vec<ChatMessage>,
-        model: &str,
-    ) -> ChatCompletionParameters {
-        let mut messages = vec![ChatMessage {
-            role: Role::System,
-            //content: ChatMessageContent::Text("You are a helpful assistant.".to_string()),
-            content: self.system_prompt_from_model(model),
-            ..Default::default()
-        }];
-        // remove system messages from user
-        let chat_messages = chat_messages
+        persona: &Persona,
+        prompt_ctx: &PromptContext,
+    ) -> Result<String, ApiError> {
+        let now = chrono::Local::now();
+        let mut vars: HashMap<&str, &str> = HashMap::new();
+        vars.extend(persona.vars.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        vars.extend(
+            prompt_ctx
+                .vars
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str())),
+        );
+
+        let date = now.format("%Y-%m-%d").to_string();
+        let datetime = now.to_rfc3339();
+        vars.insert("date", &date);
+        vars.insert("datetime", &datetime);
+        vars.insert("model", &persona.name);
+        if let Some(key_name) = &prompt_ctx.key_name {
+            vars.insert("key_name", key_name);
+        }
+        if let Some(user) = &prompt_ctx.user {
+            vars.insert("user", user);
+        }
+
+        let render_error = |e| {
+            ApiError::BadRequest(format!(
+                "Cannot render system prompt for `{}`: {}",
+                persona.name, e
+            ))
+        };
+        let template = self
+            .templates
+            .get_template(&persona.name)
+            .map_err(render_error)?;
+
+        let mut missing: Vec<String> = template
+            .undeclared_variables(false)
             .into_iter()
-            .filter(|m| m.role != Role::System)
+            .filter(|v| !vars.contains_key(v.as_str()))
             .collect();
-        messages = [messages, chat_messages].concat();
-        ChatCompletionParameters {
-            model: env::var("OAI_MODEL_NAME").expect("OAI_MODEL_NAME not set"),
-            messages,
-            ..Default::default()
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(ApiError::BadRequest(format!(
+                "Missing variables for the `{}` system prompt: {}",
+                persona.name,
+                missing.join(", ")
+            )));
         }
+
+        template.render(&vars).map_err(render_error)
+    }
+
+    /// Combines the persona prompt with the client system text according to
+    /// the persona `system_prompt_policy`.
+    fn system_prompt(
+        &self,
+        model: &str,
+        client_system: &[ChatMessage],
+        prompt_ctx: &PromptContext,
+    ) -> Result<String, ApiError> {
+        let persona = match self.persona(model) {
+            Some(persona) => persona,
+            None => return Ok("You are a helpful assistant.".to_string()),
+        };
+        let system_prompt = self.render_persona_prompt(persona, prompt_ctx)?;
+
+        let client_text = client_system
+            .iter()
+            .map(message_text)
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if client_text.is_empty() || persona.system_prompt_policy == SystemPromptPolicy::Replace {
+            return Ok(system_prompt);
+        }
+
+        if persona.system_prompt_policy == SystemPromptPolicy::Reject {
+            return Err(ApiError::BadRequest(format!(
+                "System messages are not allowed for model `{}`",
+                model
+            )));
+        }
+
+        if let Some(max) = persona.max_system_length {
+            if client_text.chars().count() > max {
+                return Err(ApiError::BadRequest(format!(
+                    "System message exceeds the maximum length of {} characters",
+                    max
+                )));
+            }
+        }
+
+        Ok(match persona.system_prompt_policy {
+            SystemPromptPolicy::Prepend => format!("{}\n\n{}", client_text, system_prompt),
+            _ => format!("{}\n\n{}", system_prompt, client_text),
+        })
     }
 }
 
 impl LlmBackend for OpenAiBackend {
     type MR = apitype::ModelList;
 
-    async fn models(&self) -> apitype::ModelList {
+    async fn models(&self) -> Result<apitype::ModelList, ApiError> {
         trace!("Fetching models from OpenAI API");
-        self.client
-            .models()
-            .list()
+        // not through `client.models()`, which panics when upstream is down
+        let response = self
+            .client
+            .build_request(reqwest::Method::GET, "/models", "application/json")
+            .send()
             .await
-            .expect("Failed to get models")
-            .into()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ApiError::Upstream(e.to_string()))?;
+        response
+            .json::<ListModelResponse>()
+            .await
+            .map(Into::into)
+            .map_err(|e| ApiError::Upstream(e.to_string()))
     }
 
-    fn from_config(config: &Config) -> Arc<Self> {
-        env::set_var("OAI_MODEL_NAME", &config.llm_model_name);
-        Arc::new(OpenAiBackend::new(
-            config.openai_api_key.clone(),
-            &config.llm_api_url,
-        ))
+    fn from_config(config: &Config) -> Result<Arc<Self>, String> {
+        OpenAiBackend::new(config.backend(&config.llm_backend), config).map(Arc::new)
     }
 
-    async fn submit_prompt(
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn context_window(&self) -> u32 {
+        self.context_window
+    }
+
+    fn assemble_prompt(
+        &self,
+        chat_messages: Vec<ChatMessage>,
+        model: &str,
+        prompt_ctx: &PromptContext,
+    ) -> Result<Prompt, ApiError> {
+        // system messages from the client are merged into the persona prompt
+        let (client_system, chat_messages): (Vec<_>, Vec<_>) = chat_messages
+            .into_iter()
+            .partition(|m| m.role == Role::System);
+
+        let mut system_prompt = self.system_prompt(model, &client_system, prompt_ctx)?;
+
+        let language = prompt_ctx
+            .language
+            .as_ref()
+            .or_else(|| self.persona(model).and_then(|p| p.language.as_ref()))
+            .and_then(|policy| language::resolve(policy, &chat_messages));
+        if let Some(language) = &language {
+            system_prompt = format!("{} {}", system_prompt, language::instruction(language));
+        }
+
+        let messages = vec![ChatMessage {
+            role: Role::System,
+            content: ChatMessageContent::Text(system_prompt),
+            ..Default::default()
+        }];
+
+        Ok(Prompt {
+            parameters: ChatCompletionParameters {
+                model: self.model.clone(),
+                messages: [messages, chat_messages].concat(),
+                ..Default::default()
+            },
+            model: model.to_string(),
+            truncation: None,
+            language,
+            redactions: Default::default(),
+            request_id: prompt_ctx.request_id.clone(),
+        })
+    }
+
+    async fn build_prompt(
         &self,
         chat_messages: Vec<ChatMessage>,
         model: &str,
-    ) -> apitype::ChatCompletionResponse {
-        let parameters = self.build_prompt(chat_messages, model);
+        prompt_ctx: &PromptContext,
+    ) -> Result<Prompt, ApiError> {
+        let mut prompt = self.assemble_prompt(chat_messages, model, prompt_ctx)?;
+
+        // redact before anything, including the summarizer, is sent upstream
+        let redact = self
+            .persona(model)
+            .and_then(|p| p.redact)
+            .unwrap_or(self.redaction.enabled);
+        if redact {
+            prompt.redactions = self
+                .redactor
+                .redact_messages(&mut prompt.parameters.messages);
+            if !prompt.redactions.is_empty() {
+                info!(
+                    "redacted {} values from `{}` prompt: {}",
+                    prompt.redactions.count(),
+                    model,
+                    prompt.redactions
+                );
+            }
+        }
+
+        // scored after redaction, the classifier model is upstream too
+        if let Some(policy) = self.injection_policy(model) {
+            let injection = self
+                .injection
+                .check(
+                    self,
+                    &prompt.parameters.messages,
+                    prompt_ctx.request_id.as_deref(),
+                )
+                .await;
+            if injection.score >= self.injection.threshold() {
+                warn!(
+                    "possible prompt injection in `{}` prompt from {}: score {:.2}, {}, policy {}",
+                    model,
+                    prompt_ctx.key_name.as_deref().unwrap_or("unknown key"),
+                    injection.score,
+                    injection,
+                    policy
+                );
+                match policy {
+                    InjectionPolicy::Block => {
+                        return Err(ApiError::BadRequest(
+                            "The prompt was rejected as a possible prompt injection".to_string(),
+                        ))
+                    }
+                    InjectionPolicy::Reinforce => {
+                        self.injection
+                            .insert_reminder(&mut prompt.parameters.messages);
+                    }
+                    InjectionPolicy::Flag | InjectionPolicy::Off => {}
+                }
+            } else {
+                debug!(
+                    "injection score of `{}` prompt: {:.2}",
+                    model, injection.score
+                );
+            }
+        }
+
+        prompt.truncation = context::fit_context(
+            self,
+            &mut prompt.parameters.messages,
+            &self.tokenizer,
+            self.context_window,
+            &self.context,
+            prompt_ctx.request_id.as_deref(),
+        )
+        .await?;
+
+        Ok(prompt)
+    }
+
+    async fn submit_prompt(
+        &self,
+        prompt: Prompt,
+    ) -> Result<apitype::ChatCompletionResponse, ApiError> {
+        let Prompt {
+            parameters,
+            model,
+            redactions,
+            request_id,
+            ..
+        } = prompt;
         //debug!("Submitting prompt to OpenAI API:\n {:#?}", parameters);
         let response = self
-            .client
-            .chat()
-            .create(parameters)
+            .chat_request(&parameters, request_id.as_deref())
+            .send()
             .await
-            .expect("Failed to get response");
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ApiError::Upstream(e.to_string()))?
+            .json::<ChatCompletionResponse>()
+            .await
+            .map_err(|e| ApiError::Upstream(e.to_string()))?;
         debug!("Response from backend: {:#?}", response);
-        response.into()
+
+        let mut response: apitype::ChatCompletionResponse = response.into();
+        response.id = completion_id(response.id, request_id.as_deref());
+        for choice in response.choices.iter_mut() {
+            if let apitype::ChatMessageContent::Text(text) = &choice.message.content {
+                let mut text = redactions.restore(text);
+                if self.moderates(&model) {
+                    let moderated = self.moderator.moderate(&text);
+                    if moderated.flagged {
+                        self.warn_filtered(&model);
+                        choice.finish_reason = Some("content_filter".to_string());
+                    }
+                    text = moderated.text;
+                }
+                choice.message.content = apitype::ChatMessageContent::Text(text);
+            }
+        }
+        Ok(response)
     }
 
-    async fn submit_prompt_stream(
-        &self,
-        chat_messages: Vec<ChatMessage>,
-        mut stream_writer: StreamWriter,
-        model: &str,
-    ) {
-        let parameters = self.build_prompt(chat_messages, model);
+    async fn submit_prompt_stream(&self, prompt: Prompt, mut stream_writer: StreamWriter) {
+        let Prompt {
+            mut parameters,
+            model,
+            redactions,
+            request_id,
+            ..
+        } = prompt;
+        parameters.stream = Some(true);
+        let moderates = self.moderates(&model);
+        let choices_expected = parameters.n.unwrap_or(1) as usize;
 
         debug!(
             "parameters:\n {}",
@@ -141,38 +517,172 @@ impl LlmBackend for OpenAiBackend {
             self.client.base_url
         );
 
-        let client = self.client.clone();
+        let event_source = match self
+            .chat_request(&parameters, request_id.as_deref())
+            .eventsource()
+        {
+            Ok(event_source) => event_source,
+            Err(e) => {
+                let error = ApiError::Upstream(e.to_string());
+                error!("stream not started: {}", error);
+                let _ = stream_writer.write(error.body().to_string()).await;
+                return;
+            }
+        };
+        let mut resp_stream =
+            Client::process_stream::<ChatCompletionChunkResponse>(event_source).await;
 
-        let mut resp_stream = client
-            .chat()
-            .create_stream(parameters)
-            .await
-            .expect("Failed to get response");
+        let new_output = || ChoiceOutput {
+            restorer: redactions.restorer(),
+            filter: moderates.then(|| self.moderator.filter()),
+            finished: false,
+        };
+        let mut outputs: BTreeMap<u32, ChoiceOutput> = BTreeMap::new();
+        // of the last chunk, for the one releasing held back text
+        let mut last_chunk: Option<(String, u32, String)> = None;
+        let mut failure: Option<ApiError> = None;
 
         while let Some(response) = resp_stream.next().await {
-            let response: ChatCompletionChunkResponse = response.expect("Failed to get response");
+            let response: ChatCompletionChunkResponse = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    // the event source retries on its own, stop at the first error
+                    let error = ApiError::Upstream(e.to_string());
+                    error!("stream of `{}` failed: {}", model, error);
+                    failure = Some(error);
+                    break;
+                }
+            };
 
             debug!("Response from backend: {:#?}", response);
 
+            let mut choices: Vec<apitype::ChatCompletionChunkChoice> = response
+                .choices
+                .clone()
+                .into_iter()
+                .map(|c| c.into())
+                .collect();
+            for choice in choices.iter_mut() {
+                let output = outputs
+                    .entry(choice.index.unwrap_or_default())
+                    .or_insert_with(new_output);
+                let finished = choice.finish_reason.is_some();
+                let flagged = output.is_flagged();
+                let content = output.push(choice.delta.content.as_deref().unwrap_or(""), finished);
+                if output.is_flagged() && !flagged {
+                    self.warn_filtered(&model);
+                }
+                if output.is_blocked() || (finished && output.is_flagged()) {
+                    choice.finish_reason = Some(FinishReason::ContentFilterFlagged);
+                }
+                if choice.delta.content.is_some() || !content.is_empty() {
+                    choice.delta.content = Some(content);
+                }
+            }
+
+            let id = completion_id(response.id, request_id.as_deref());
+            last_chunk = Some((id.clone(), response.created, response.object.clone()));
             let data = apitype::ChatCompletionChunkResponse {
-                id: response.id,
-                choices: response
-                    .choices
-                    .clone()
-                    .into_iter()
-                    .map(|c| c.into())
-                    .collect(),
+                id,
+                choices,
                 created: response.created,
                 object: response.object,
-                model: Some(model.to_string()),
+                model: Some(model.clone()),
+                system_fingerprint: None,
+            };
+
+            let data = serde_json::to_string(&data).expect("Failed to serialize response");
+            if let Err(e) = stream_writer.write(data).await {
+                // nothing relays it anymore, dropping the upstream stream ends it
+                debug!("stream of `{}` no longer relayed, stopping: {}", model, e);
+                return;
+            }
+
+            // stop reading upstream once every choice is cut off
+            if outputs.len() >= choices_expected && outputs.values().all(ChoiceOutput::is_blocked) {
+                break;
+            }
+        }
+
+        // upstream may end the stream without a finish_reason, release what
+        // is still held back
+        let choices: Vec<apitype::ChatCompletionChunkChoice> = outputs
+            .iter_mut()
+            .filter(|(_, output)| !output.finished && !output.is_blocked())
+            .filter_map(|(index, output)| {
+                let flagged = output.is_flagged();
+                let content = output.push("", true);
+                if output.is_flagged() && !flagged {
+                    self.warn_filtered(&model);
+                }
+                let finish_reason = output
+                    .is_flagged()
+                    .then_some(FinishReason::ContentFilterFlagged);
+                if content.is_empty() && finish_reason.is_none() {
+                    return None;
+                }
+                Some(apitype::ChatCompletionChunkChoice {
+                    index: Some(*index),
+                    delta: apitype::DeltaChatMessage {
+                        role: None,
+                        content: Some(content),
+                        tool_calls: None,
+                    },
+                    logprobs: None,
+                    finish_reason,
+                })
+            })
+            .collect();
+        if let Some((id, created, object)) = last_chunk.filter(|_| !choices.is_empty()) {
+            let data = apitype::ChatCompletionChunkResponse {
+                id,
+                choices,
+                created,
+                object,
+                model: Some(model.clone()),
                 system_fingerprint: None,
             };
+            let data = serde_json::to_string(&data).expect("Failed to serialize response");
+            if let Err(e) = stream_writer.write(data).await {
+                debug!("stream of `{}` no longer relayed, stopping: {}", model, e);
+                return;
+            }
+        }
 
-            stream_writer
-                //.write(serde_json::to_string(&response).expect("Failed to serialize response"))
-                .write(serde_json::to_string(&data).expect("Failed to serialize response"))
-                .await
-                .expect("Failed to write to stream");
+        if let Some(error) = failure {
+            let _ = stream_writer.write(error.body().to_string()).await;
         }
     }
+
+    async fn complete(
+        &self,
+        chat_messages: Vec<ChatMessage>,
+        upstream_model: &str,
+        request_id: Option<&str>,
+    ) -> Result<String, ApiError> {
+        let parameters = ChatCompletionParameters {
+            model: upstream_model.to_string(),
+            messages: chat_messages,
+            ..Default::default()
+        };
+        let response = self
+            .chat_request(&parameters, request_id)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ApiError::Upstream(e.to_string()))?
+            .json::<ChatCompletionResponse>()
+            .await
+            .map_err(|e| ApiError::Upstream(e.to_string()))?;
+
+        Ok(response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| match c.message.content {
+                ChatMessageContent::Text(text) => Some(text),
+                _ => None,
+            })
+            .unwrap_or_default())
+    }
 }
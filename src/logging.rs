@@ -0,0 +1,139 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Logs and request ids.
+//!
+//! Every request gets an `x-request-id`, the one of the client when it sends
+//! a usable one. It is returned with the response, sent upstream and kept in
+//! a `request` span with the key name, persona and upstream model once they
+//! are known, so every event logged while serving the request carries them.
+//! A last event reports the status and latency.
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage, HttpRequest,
+};
+use futures::future::LocalBoxFuture;
+use std::{
+    io::{self, IsTerminal},
+    time::Instant,
+};
+use tracing::{field::Empty, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogConfig, LogFormat};
+use crate::thread::generate_id;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id taken from a client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Installs the logger of `[log]`, the `log` records of the dependencies
+/// included.
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(config.level()))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    // on stderr like `env_logger` did, colored only on a terminal
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    match config.format {
+        LogFormat::Text => logger.init(),
+        LogFormat::Json => logger
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+/// Id of the request, set by [`middleware`].
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of(req: &HttpRequest) -> Option<String> {
+        req.extensions().get::<RequestId>().map(|id| id.0.clone())
+    }
+}
+
+/// Ids of clients are only taken when they are short and safe to log and
+/// send upstream.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Sets the request id and the span of the request, and logs its outcome.
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(String::from)
+        .unwrap_or_else(|| generate_id("req"));
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        path = %req.path(),
+        key_name = Empty,
+        persona = Empty,
+        upstream = Empty,
+    );
+    let started = Instant::now();
+    let response = span.in_scope(|| srv.call(req));
+
+    Box::pin(
+        async move {
+            let result = response.await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            // streams are still being relayed, their latency is until the
+            // headers were sent
+            tracing::info!(
+                status = status.as_u16(),
+                latency_ms = started.elapsed().as_millis() as u64,
+                "request finished"
+            );
+
+            let mut res = result?;
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        }
+        .instrument(span),
+    )
+}
@@ -23,13 +23,31 @@ use clap::{Parser, Subcommand};
 use serde::Deserialize;
 use std::{fs, io::ErrorKind, path::Path, process::exit};
 
+mod admin;
 mod apitype;
 mod appctx;
+mod audit;
+mod auth;
 mod config;
+mod context;
+mod cors;
 mod endpoint;
+mod error;
+mod guardrail;
+mod health;
+mod jwt;
+mod language;
 mod llm;
+mod logging;
+mod playground;
+mod secret;
 mod server;
 mod streamer;
+mod tenant;
+mod thread;
+mod tls;
+mod tokenizer;
+mod usage;
 
 use config::Config;
 
@@ -51,13 +69,32 @@ enum Commands {
         #[arg(short, long, default_value = "default.conf")]
         config: String,
 
-        #[arg(short, long, help = "Listen address, default 127.0.0.1")]
+        #[arg(
+            short,
+            long,
+            help = "Listen address, `host`, `host:port` or `unix:<path>`, default 127.0.0.1"
+        )]
         listen: Option<String>,
 
         #[arg(short, long, help = "Listen port, default: 8080")]
         port: Option<u16>,
     },
 
+    #[command(about = "Validate a config file")]
+    CheckConfig {
+        #[arg(short, long, default_value = "default.conf")]
+        config: String,
+    },
+
+    #[command(about = "List the config sources, or print the merged config")]
+    PrintConfig {
+        #[arg(short, long, default_value = "default.conf")]
+        config: String,
+
+        #[arg(long, help = "Print the merged config, credentials masked")]
+        effective: bool,
+    },
+
     #[command(about = "Add API key")]
     AddApiKey {
         #[arg(short, long, default_value = "default.conf")]
@@ -71,9 +108,12 @@ enum Commands {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
 
     let args = Args::parse();
+    // the server logs as its config says, once loaded
+    if !matches!(args.command, Commands::Serve { .. }) {
+        logging::init(&Default::default());
+    }
 
     match args.command {
         Commands::Serve {
@@ -81,54 +121,82 @@ async fn main() -> std::io::Result<()> {
             listen,
             port,
         } => {
-            println!("Value for config: {}", config);
-
-            let config: Config = match fs::read_to_string(&config) {
-                Ok(config) => toml::from_str(&config).unwrap(),
-                Err(e) => {
-                    if e.kind() == ErrorKind::NotFound {
-                        println!("`{}` not exists.", config);
-                        exit(2);
-                    } else {
-                        panic!("Error: {}", e);
-                    }
-                }
-            };
-            println!("Config: {:#?}", config);
-            server::run(config, listen.as_deref(), port).await?
+            let config_path = config;
+            let config = config::load(&config_path).unwrap_or_else(|errors| {
+                errors.iter().for_each(|e| eprintln!("{}", e));
+                exit(2);
+            });
+            logging::init(&config.log);
+            info!("Config loaded from {}", config_path);
+            info!("{}", config.summary());
+            if let Err(e) = server::run(config, &config_path, listen.as_deref(), port).await {
+                error!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::CheckConfig { config } => match config::load(&config) {
+            Ok(_) => println!("{}: OK", config),
+            Err(errors) => {
+                errors.iter().for_each(|e| eprintln!("{}", e));
+                exit(1);
+            }
+        },
+        Commands::PrintConfig { config, effective } => {
+            let sources = config::sources(&config);
+            if !effective {
+                sources.iter().for_each(|source| println!("{}", source));
+                return Ok(());
+            }
+
+            let config = config::load(&config).unwrap_or_else(|errors| {
+                errors.iter().for_each(|e| eprintln!("{}", e));
+                exit(1);
+            });
+            sources.iter().for_each(|source| println!("# {}", source));
+            println!();
+            print!(
+                "{}",
+                toml::to_string(&config.redacted()).expect("Cannot serialize config")
+            );
         }
         Commands::AddApiKey { config, name } => {
             println!("Add API key");
 
-            if let Ok(config_str) = fs::read_to_string(&config) {
-                let mut conf: Config = toml::from_str(&config_str).expect("Cannot parse config");
-                let key = generate_key();
-                conf.api_keys.push(config::ApiKey {
-                    key: key.clone(),
-                    name,
-                    description: None,
-                    permissions: vec!["read".to_string()],
-                });
-                let config_str = toml::to_string(&conf).expect("Cannot serialize config");
-                fs::write(&config, config_str).expect("Cannot write config");
-                println!("API key added: {}", key);
-                //println!("Config: {:#?}", config);
+            let config_str = fs::read_to_string(&config).unwrap_or_else(|e| {
+                eprintln!("Cannot read {}: {}", config, e);
+                exit(1);
+            });
+            // not interpolated, `${VAR}` and `file:` values are written back as is
+            let mut conf: Config = toml::from_str(&config_str).unwrap_or_else(|e| {
+                eprintln!("Cannot parse {}: {}", config, e);
+                exit(1);
+            });
+            // key names are unique, across the conf.d fragments too
+            let layered = config::load(&config)
+                .map(|c| c.api_keys)
+                .unwrap_or_default();
+            if conf.api_keys.iter().chain(&layered).any(|k| k.name == name) {
+                eprintln!("An API key named `{}` already exists", name);
+                exit(1);
             }
+
+            let key = secret::generate_key();
+            conf.api_keys.push(config::ApiKey {
+                key: secret::Secret::new(key.clone()),
+                name,
+                description: None,
+                permissions: vec!["read".to_string()],
+                language: None,
+                client_cert: None,
+                projects: Vec::new(),
+                policy: Default::default(),
+                restrictions: Default::default(),
+            });
+            let config_str = toml::to_string(&conf).expect("Cannot serialize config");
+            fs::write(&config, config_str).expect("Cannot write config");
+            println!("API key added: {}", key);
         }
     }
 
     Ok(())
 }
-
-fn generate_key() -> String {
-    use rand::distributions::Alphanumeric;
-    use rand::{thread_rng, Rng};
-    let code: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .collect::<Vec<u8>>()
-        .into_iter()
-        .map(char::from)
-        .collect();
-    format!("nsk-{}", code)
-}
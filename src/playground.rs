@@ -0,0 +1,150 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! The web pages: the playground at `/` and the usage dashboard at
+//! `/dashboard`.
+//!
+//! Their assets are embedded in the binary. Pages are revalidated on every
+//! load, the scripts and styles they link carry a content hash in their URL
+//! and are cached for good.
+
+use actix_web::{
+    http::header::{self, CacheControl, CacheDirective, ETag, EntityTag, HeaderValue, IfNoneMatch},
+    web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+
+const INDEX_HTML: &str = include_str!("../static/index.html");
+const DASHBOARD_HTML: &str = include_str!("../static/dashboard.html");
+
+/// Assets served under `/static`, by file name.
+const ASSETS: &[(&str, &str, &str)] = &[
+    (
+        "playground.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../static/playground.js"),
+    ),
+    (
+        "dashboard.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../static/dashboard.js"),
+    ),
+    (
+        "playground.css",
+        "text/css; charset=utf-8",
+        include_str!("../static/playground.css"),
+    ),
+];
+
+/// Only the page's own scripts, styles and API calls, nothing inline.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'self'; \
+     style-src 'self'; connect-src 'self'; img-src 'self' data:; base-uri 'none'; \
+     form-action 'none'; frame-ancestors 'none'";
+
+fn digest(content: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, content.as_bytes());
+    digest.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+lazy_static! {
+    /// Changes whenever any of the assets does, busting their cache.
+    static ref ASSETS_VERSION: String = digest(
+        &ASSETS
+            .iter()
+            .map(|(_, _, content)| *content)
+            .collect::<String>()
+    );
+    static ref INDEX: String = INDEX_HTML.replace("{{version}}", &ASSETS_VERSION);
+    static ref DASHBOARD: String = DASHBOARD_HTML.replace("{{version}}", &ASSETS_VERSION);
+}
+
+fn not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+/// The content, or 304 when the client has it already.
+fn respond(
+    req: &HttpRequest,
+    content_type: &str,
+    content: &'static str,
+    cache: Vec<CacheDirective>,
+) -> HttpResponse {
+    let etag = EntityTag::new_strong(digest(content));
+    let fresh = not_modified(req, &etag);
+    let mut response = match fresh {
+        true => HttpResponse::NotModified(),
+        false => HttpResponse::Ok(),
+    };
+    response
+        .insert_header(ETag(etag))
+        .insert_header(CacheControl(cache))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    match fresh {
+        true => response.finish(),
+        false => response.content_type(content_type).body(content),
+    }
+}
+
+fn page(req: &HttpRequest, html: &'static str) -> HttpResponse {
+    let mut response = respond(
+        req,
+        "text/html; charset=utf-8",
+        html,
+        vec![CacheDirective::NoCache],
+    );
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+    );
+    headers.insert(
+        header::REFERRER_POLICY,
+        HeaderValue::from_static("no-referrer"),
+    );
+    response
+}
+
+pub async fn index(req: HttpRequest) -> impl Responder {
+    page(&req, INDEX.as_str())
+}
+
+pub async fn dashboard(req: HttpRequest) -> impl Responder {
+    page(&req, DASHBOARD.as_str())
+}
+
+pub async fn asset(req: HttpRequest, name: web::Path<String>) -> impl Responder {
+    let (content_type, content) = match ASSETS.iter().find(|(file, _, _)| *file == *name) {
+        Some((_, content_type, content)) => (*content_type, *content),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    // without the current version the URL is not content addressed
+    let versioned = req
+        .query_string()
+        .split('&')
+        .any(|pair| pair == format!("v={}", *ASSETS_VERSION));
+    let cache = match versioned {
+        true => vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(31_536_000),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ],
+        false => vec![CacheDirective::NoCache],
+    };
+    respond(&req, content_type, content, cache)
+}
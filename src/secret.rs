@@ -0,0 +1,126 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Credentials that must never end up in logs.
+
+use std::fmt;
+
+const REDACTED: &str = "********";
+/// Prefix of a secret kept as the SHA-256 of the token, in hex.
+const SHA256_PREFIX: &str = "sha256:";
+
+/// An API key or token. `Debug` and `Display` never show the value, use
+/// [`Secret::expose`] where the value itself is needed. Serializes as the
+/// plain string so configs round-trip.
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<T: Into<String>>(value: T) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The placeholder shown instead of the value, for printing configs.
+    pub fn masked(&self) -> Self {
+        Secret(REDACTED.to_string())
+    }
+
+    /// The SHA-256 of the value, which still matches the same tokens, for
+    /// secrets stored where they could be read.
+    pub fn hashed(&self) -> Self {
+        if self.is_hashed() {
+            return self.clone();
+        }
+        Secret(format!("{}{}", SHA256_PREFIX, sha256_hex(&self.0)))
+    }
+
+    pub fn is_hashed(&self) -> bool {
+        self.0.starts_with(SHA256_PREFIX)
+    }
+
+    /// Compares with a presented token in constant time.
+    pub fn matches(&self, token: &str) -> bool {
+        match self.0.strip_prefix(SHA256_PREFIX) {
+            Some(hash) => constant_time_eq(hash, &sha256_hex(token)),
+            None => constant_time_eq(&self.0, token),
+        }
+    }
+}
+
+fn sha256_hex(value: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, value.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// A new `nsk-` API key.
+pub fn generate_key() -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+    let code: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .collect::<Vec<u8>>()
+        .into_iter()
+        .map(char::from)
+        .collect();
+    format!("nsk-{}", code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_secrets_match_the_same_tokens() {
+        let secret = Secret::new("nsk-12345abc1");
+        let hashed = secret.hashed();
+        assert!(hashed.is_hashed());
+        assert!(!hashed.expose().contains("12345abc1"));
+        assert!(hashed.matches("nsk-12345abc1"));
+        assert!(!hashed.matches("nsk-12345abc2"));
+        assert!(!hashed.matches(hashed.expose()));
+        assert_eq!(hashed.hashed(), hashed);
+    }
+
+    #[test]
+    fn plain_secrets_match_exactly() {
+        let secret = Secret::new("nsk-12345abc1");
+        assert!(secret.matches("nsk-12345abc1"));
+        assert!(!secret.matches("nsk-12345abc"));
+        assert!(!secret.matches(""));
+    }
+}
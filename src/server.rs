@@ -12,108 +12,368 @@
 //
 
 use actix::{Actor, ActorContext, StreamHandler};
+use actix_web::dev::Service;
 use actix_web::{
-    dev::ServiceRequest, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    dev::ServiceRequest, web, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+    Responder,
 };
 use actix_web_actors::ws;
 use actix_web_httpauth::{
-    extractors::bearer::{self, BearerAuth},
+    extractors::{
+        bearer::{self, BearerAuth},
+        AuthenticationError,
+    },
     middleware::HttpAuthentication,
 };
 use parking_lot::Mutex;
-use std::{net::TcpStream, sync::Arc};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{fs, io, net::TcpStream, sync::Arc};
 use tokio::{net::TcpSocket, sync::mpsc};
 
 use crate::appctx::AppContext;
-use crate::config::Config;
+use crate::audit::AuditLog;
+use crate::auth::{self, Caller};
+use crate::config::{self, AuthConfig, Config, Listen, ScopeAuth, ADMIN_PERMISSION};
+use crate::health::{self, UpstreamProbe};
 use crate::llm::{LlmBackend, OpenAiBackend};
-use crate::{apitype, endpoint};
+use crate::{admin, apitype, cors, endpoint, logging, playground, thread, tls, usage};
 
-async fn index_html() -> impl Responder {
-    HttpResponse::Ok().body(include_str!("../static/index.html"))
-}
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
 
-mod auth {
-    use crate::config::Config;
+/// A valid bearer token, or a client certificate mapped to an API key.
+async fn bearer_validator(
+    req: ServiceRequest,
+    credentials: Option<BearerAuth>,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let ctx = req
+        .app_data::<web::Data<AppContext<OpenAiBackend>>>()
+        .unwrap()
+        .clone();
+    let config = ctx.config();
 
-    pub fn validate_token(token: &str, config: &Config) -> bool {
-        config.api_keys.iter().any(|key| key.key == token)
+    let caller = match credentials {
+        Some(credentials) => {
+            let token = credentials.token();
+            // a mapped client certificate pins the connection to its key
+            if let Some(key) = tls::client_cert_key(&req, &config) {
+                if !key.key.matches(token) {
+                    warn!(
+                        "bearer token does not match the client certificate of `{}`",
+                        key.name
+                    );
+                    return Err((actix_web::error::ErrorUnauthorized("Unauthorized"), req));
+                }
+            }
+            match Caller::authenticate(&config, &ctx.jwks, token).await {
+                Some(caller) => caller,
+                None => return Err((actix_web::error::ErrorUnauthorized("Unauthorized"), req)),
+            }
+        }
+        // authenticated by its client certificate, see `tls::authorize_client_cert`
+        None => {
+            let caller = req.extensions_mut().remove::<Caller>();
+            match caller {
+                Some(caller) => caller,
+                None => {
+                    return Err((
+                        AuthenticationError::from(bearer::Config::default()).into(),
+                        req,
+                    ))
+                }
+            }
+        }
+    };
+    tracing::Span::current().record("key_name", caller.key.name.as_str());
+    if let Err(e) = auth::check_restrictions(&req, &config, &caller.key) {
+        warn!("API key `{}` refused: {}", caller.key.name, e);
+        return Err((e.into(), req));
     }
+    req.extensions_mut().insert(caller);
+    Ok(req)
 }
 
-async fn bearer_validator(
+/// A caller of [`bearer_validator`] with the `admin` permission.
+async fn admin_validator(
     req: ServiceRequest,
-    credentials: BearerAuth,
+    credentials: Option<BearerAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let config = req
-        .app_data::<web::Data<Config>>()
-        .map(|data| data.as_ref())
+    let req = bearer_validator(req, credentials).await?;
+    let is_admin = req
+        .extensions()
+        .get::<Caller>()
+        .is_some_and(|caller| caller.key.has_permission(ADMIN_PERMISSION));
+    match is_admin {
+        true => Ok(req),
+        false => Err((actix_web::error::ErrorForbidden("Forbidden"), req)),
+    }
+}
+
+/// Auth of a scope configured in `[auth]`, `scope` picks its setting.
+async fn scope_validator(
+    req: ServiceRequest,
+    credentials: Option<BearerAuth>,
+    scope: fn(&AuthConfig) -> ScopeAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let auth = req
+        .app_data::<web::Data<AppContext<OpenAiBackend>>>()
+        .map(|ctx| scope(&ctx.config().auth))
         .unwrap();
-    let token = credentials.token();
-    trace!("In bearer_validator, got token: {}", token);
-
-    if !token.is_empty() {
-        if auth::validate_token(token, config) {
-            Ok(req)
-        } else {
-            Err((actix_web::error::ErrorUnauthorized("Unauthorized"), req))
-        }
-    } else {
-        Err((actix_web::error::ErrorUnauthorized("Unauthorized"), req))
+    match auth {
+        ScopeAuth::Public => Ok(req),
+        ScopeAuth::Bearer => bearer_validator(req, credentials).await,
     }
 }
 
-fn get_listen_address_and_port<'a>(
-    config: &'a Config,
-    listen: Option<&'a str>,
-    port: Option<u16>,
-) -> (&'a str, u16) {
-    let _listen_default: &'static str = "127.0.0.1";
-    match (listen, port) {
-        (Some(listen), Some(port)) => (listen, port),
-        (None, Some(port)) => (_listen_default, port),
-        (Some(listen), None) => (listen, 8080),
-        _ => match config.listen {
-            Some(ref listen) => {
-                let mut parts = listen.split(':');
-                match (parts.next(), parts.next()) {
-                    (Some(host), Some(port)) => (host, port.parse().expect("port integer")),
-                    _ => (_listen_default, 8080),
-                }
-            }
-            None => (_listen_default, 8080),
+/// Resolves the listen address from `--listen`, `--port` and the config.
+/// `--listen` without a port keeps the port of the config, 8080 by default.
+fn listen_address(config: &Config, listen: Option<&str>, port: Option<u16>) -> io::Result<Listen> {
+    let parse = |listen: &str| {
+        Listen::parse(listen).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let configured = match &config.listen {
+        Some(listen) => parse(listen)?,
+        None => Listen::Tcp {
+            host: DEFAULT_HOST.to_string(),
+            port: None,
         },
+    };
+    let configured_port = match &configured {
+        Listen::Tcp { port, .. } => *port,
+        Listen::Unix(_) => None,
+    };
+
+    let listen = match listen {
+        Some(listen) => parse(listen)?,
+        None => configured,
+    };
+    match listen {
+        Listen::Tcp {
+            host,
+            port: listen_port,
+        } => Ok(Listen::Tcp {
+            host,
+            port: Some(
+                port.or(listen_port)
+                    .or(configured_port)
+                    .unwrap_or(DEFAULT_PORT),
+            ),
+        }),
+        Listen::Unix(_) if port.is_some() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--port cannot be used with a unix socket",
+        )),
+        unix => Ok(unix),
+    }
+}
+
+fn display_address(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
     }
 }
 
-pub async fn run(config: Config, listen: Option<&str>, port: Option<u16>) -> std::io::Result<()> {
-    // Start the server
-    let (host, port) = get_listen_address_and_port(&config, listen, port);
-
-    println!("Starting server at http://{}:{}", host, port);
-
-    let config = config.clone();
-
-    HttpServer::new(move || {
-        let mut app = App::new()
-            .app_data(web::Data::from(Arc::new(config.clone())))
-            .wrap(HttpAuthentication::bearer(bearer_validator))
-            .service(endpoint::chat_completions)
-            .service(endpoint::models)
-            .route("/", web::get().to(index_html));
-
-        if config.llm_backend == "openai" {
-            debug!("use OpenAI backend");
-            app = app.app_data(web::Data::from(AppContext::<OpenAiBackend>::from_config(
-                &config,
-            )));
-        } else {
-            println!("Unknown LLM backend: {}", config.llm_backend);
+/// Reloads the config file on SIGHUP. API keys, personas, guardrails and the
+/// backend settings are swapped at once, streams in flight finish on the
+/// backend they started with. `listen` and `audit` changes need a restart.
+#[cfg(unix)]
+async fn reload_on_hangup(ctx: Arc<AppContext<OpenAiBackend>>, config_path: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("cannot listen for SIGHUP, config reload disabled: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading {}", config_path);
+        let config = match config::load(&config_path) {
+            Ok(config) => config,
+            Err(errors) => {
+                errors
+                    .iter()
+                    .for_each(|e| error!("config not reloaded: {}", e));
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                ctx.set_reload_error(Some(errors.join("; ")));
+                continue;
+            }
+        };
+
+        let current = ctx.config();
+        if current.listen != config.listen {
+            warn!("`listen` changed, restart to apply");
+        }
+        if current.tls != config.tls {
+            warn!("`tls` changed, restart to apply");
+        }
+        if current.audit != config.audit {
+            warn!("`audit` changed, restart to apply");
         }
 
-        app
+        match ctx.reload(config) {
+            Ok(()) => {
+                info!("config reloaded from {}", config_path);
+                ctx.set_reload_error(None);
+            }
+            Err(e) => {
+                error!("config not reloaded: {}", e);
+                ctx.set_reload_error(Some(e));
+            }
+        }
+    }
+}
+
+pub async fn run(
+    config: Config,
+    config_path: &str,
+    listen: Option<&str>,
+    port: Option<u16>,
+) -> std::io::Result<()> {
+    let listen = listen_address(&config, listen, port)?;
+    let tls = config.tls.clone();
+
+    // shared by all workers, so a reload reaches every one of them
+    debug!("use {} backend", config.llm_backend);
+    let audit =
+        AuditLog::new(&config.audit).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let audit = Arc::new(audit);
+    let ctx = AppContext::<OpenAiBackend>::from_config(&config, audit)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload_on_hangup(ctx.clone(), config_path.to_string()));
+
+    let probe = web::Data::new(UpstreamProbe::default());
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::from(ctx.clone()))
+            .app_data(probe.clone())
+            .wrap_fn(|mut req, srv| {
+                tls::authorize_client_cert(&mut req);
+                srv.call(req)
+            })
+            // preflights are answered before any authentication
+            .wrap_fn(cors::middleware)
+            // outermost, refused requests get an id and are logged too
+            .wrap_fn(logging::middleware)
+            // scopes the `[auth]` section may open to the public
+            .service(
+                web::resource("/healthz")
+                    .wrap(HttpAuthentication::with_fn(|req, credentials| {
+                        scope_validator(req, credentials, |auth| auth.health)
+                    }))
+                    .route(web::get().to(health::healthz)),
+            )
+            .service(
+                web::resource("/readyz")
+                    .wrap(HttpAuthentication::with_fn(|req, credentials| {
+                        scope_validator(req, credentials, |auth| auth.health)
+                    }))
+                    .route(web::get().to(health::readyz)),
+            )
+            .service(
+                web::resource("/")
+                    .wrap(HttpAuthentication::with_fn(|req, credentials| {
+                        scope_validator(req, credentials, |auth| auth.ui)
+                    }))
+                    .route(web::get().to(playground::index))
+                    .route(web::head().to(playground::index)),
+            )
+            .service(
+                web::resource("/dashboard")
+                    .wrap(HttpAuthentication::with_fn(|req, credentials| {
+                        scope_validator(req, credentials, |auth| auth.ui)
+                    }))
+                    .route(web::get().to(playground::dashboard))
+                    .route(web::head().to(playground::dashboard)),
+            )
+            .service(
+                web::resource("/static/{name}")
+                    .wrap(HttpAuthentication::with_fn(|req, credentials| {
+                        scope_validator(req, credentials, |auth| auth.ui)
+                    }))
+                    .route(web::get().to(playground::asset))
+                    .route(web::head().to(playground::asset)),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(HttpAuthentication::with_fn(admin_validator))
+                    .service(admin::list_api_keys)
+                    .service(admin::create_api_key)
+                    .service(admin::get_api_key)
+                    .service(admin::update_api_key)
+                    .service(admin::delete_api_key)
+                    .service(admin::list_personas)
+                    .service(admin::create_persona)
+                    .service(admin::get_persona)
+                    .service(admin::update_persona)
+                    .service(admin::delete_persona)
+                    .service(admin::list_models)
+                    .service(admin::create_model)
+                    .service(admin::get_model)
+                    .service(admin::update_model)
+                    .service(admin::delete_model)
+                    .service(admin::usage)
+                    .service(admin::streams),
+            )
+            // the API, always behind a bearer token
+            .service(
+                web::scope("")
+                    .wrap(HttpAuthentication::with_fn(bearer_validator))
+                    .service(endpoint::chat_completions)
+                    .service(endpoint::models)
+                    .service(endpoint::tokenize)
+                    .service(endpoint::count_tokens)
+                    .service(usage::usage)
+                    .service(thread::create_thread)
+                    .service(thread::get_thread)
+                    .service(thread::delete_thread)
+                    .service(thread::create_message)
+                    .service(thread::list_messages)
+                    .service(thread::create_run),
+            )
     })
-    .bind((host, port))?
-    .run()
-    .await
+    .on_connect(tls::on_connect);
+
+    let server = match (listen, tls) {
+        (Listen::Tcp { host, port }, Some(tls)) => {
+            let port = port.unwrap_or(DEFAULT_PORT);
+            let (server_config, resolver) = tls::server_config(&tls)?;
+            actix_web::rt::spawn(tls::reload_on_change(resolver, tls));
+            info!(
+                "Starting server at https://{}",
+                display_address(&host, port)
+            );
+            server.bind_rustls_0_22((host, port), server_config)?
+        }
+        (Listen::Tcp { host, port }, None) => {
+            let port = port.unwrap_or(DEFAULT_PORT);
+            info!("Starting server at http://{}", display_address(&host, port));
+            server.bind((host, port))?
+        }
+        #[cfg(unix)]
+        (Listen::Unix(path), _) => {
+            // left over by a previous run
+            if fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                fs::remove_file(&path)?;
+            }
+            info!("Starting server at unix:{}", path.display());
+            server.bind_uds(path)?
+        }
+        #[cfg(not(unix))]
+        (Listen::Unix(_), _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))
+        }
+    };
+
+    server.run().await
 }
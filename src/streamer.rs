@@ -1,15 +1,31 @@
-use actix_web::rt::{self, time::interval, Runtime};
+use actix_web::{
+    http::StatusCode,
+    rt::{self, time::interval, Runtime},
+    web, HttpResponse, HttpResponseBuilder,
+};
 use actix_web_lab::{
     sse::{self, Sse},
     util::InfallibleStream,
 };
+use chrono::{DateTime, Utc};
 use futures_util::future;
+use openai_dive::v1::resources::shared::FinishReason;
 use parking_lot::Mutex;
-use std::{io::Write, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::io::AsyncWrite;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 
+use crate::apitype;
+
 pub struct StreamWriter(pub Arc<mpsc::Sender<String>>);
 
 impl StreamWriter {
@@ -23,8 +39,125 @@ impl StreamWriter {
         self.0
             .send(msg.as_ref().to_string())
             .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            .map_err(std::io::Error::other)?;
 
         Ok(msg.as_ref().len())
     }
 }
+
+/// The answer assembled from a relayed stream.
+#[derive(Debug, Default)]
+pub struct Relayed {
+    pub content: String,
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A stream being relayed to a client.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamInfo {
+    pub id: u64,
+    pub endpoint: String,
+    pub key_name: Option<String>,
+    pub persona: String,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Streams in flight, listed by the admin API.
+#[derive(Default)]
+pub struct StreamRegistry {
+    next_id: AtomicU64,
+    streams: Mutex<BTreeMap<u64, StreamInfo>>,
+}
+
+impl StreamRegistry {
+    /// Registers a stream until the returned guard is dropped.
+    pub fn register(
+        self: &Arc<Self>,
+        endpoint: &str,
+        key_name: Option<String>,
+        persona: &str,
+    ) -> ActiveStream {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.streams.lock().insert(
+            id,
+            StreamInfo {
+                id,
+                endpoint: endpoint.to_string(),
+                key_name,
+                persona: persona.to_string(),
+                started_at: Utc::now(),
+            },
+        );
+        ActiveStream {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    pub fn list(&self) -> Vec<StreamInfo> {
+        self.streams.lock().values().cloned().collect()
+    }
+}
+
+pub struct ActiveStream {
+    registry: Arc<StreamRegistry>,
+    id: u64,
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.registry.streams.lock().remove(&self.id);
+    }
+}
+
+/// Forwards serialized chunks from `rx` to `writer` and returns the assistant
+/// answer assembled from the chunk deltas.
+///
+/// The backend is drained to the end even when the client goes away, so the
+/// caller always gets the complete answer.
+pub async fn relay(mut rx: mpsc::Receiver<String>, mut writer: StreamWriter) -> Relayed {
+    let mut relayed = Relayed::default();
+    let mut client_gone = false;
+
+    while let Some(event) = rx.recv().await {
+        if let Ok(chunk) = serde_json::from_str::<apitype::ChatCompletionChunkResponse>(&event) {
+            for choice in chunk.choices {
+                if let Some(content) = choice.delta.content {
+                    relayed.content.push_str(&content);
+                }
+                if choice.finish_reason.is_some() {
+                    relayed.finish_reason = choice.finish_reason;
+                }
+            }
+        }
+
+        if !client_gone && writer.write(&event).await.is_err() {
+            debug!("client disconnected, draining stream");
+            client_gone = true;
+        }
+    }
+
+    relayed
+}
+
+/// Builds a server-sent events response from the chunks received on `rx`,
+/// terminated by the `[DONE]` message.
+pub fn event_stream(
+    mut response: HttpResponseBuilder,
+    mut rx: mpsc::Receiver<String>,
+) -> HttpResponse {
+    response
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(Box::pin(async_stream::stream! {
+            while let Some(event) = rx.recv().await {
+                debug!("++Event: {}", event);
+                yield Ok::<_,actix_web::error::Error>(web::Bytes::from(["data: ", &event, "\n\n"].concat()));
+            }
+
+            // send [DONE] message
+            yield Ok::<_,actix_web::error::Error>(web::Bytes::from("data: [DONE]\n\n"));
+
+            trace!("[*] STREAM CLOSED.");
+        }))
+}
@@ -0,0 +1,329 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Organizations and projects of API keys.
+//!
+//! A key in several projects works for the first one, unless the request
+//! picks another with the `OpenAI-Project` header, or the first in another
+//! organization with `OpenAI-Organization`. Keys without a project ignore
+//! both headers.
+
+use actix_web::HttpRequest;
+use derive_more::Display;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{ApiKey, Config, KeyPolicy},
+    error::ApiError,
+};
+
+const ORGANIZATION_HEADER: &str = "openai-organization";
+const PROJECT_HEADER: &str = "openai-project";
+
+/// Organization and project a request is made for, and the policy its key
+/// gets from them.
+#[derive(Debug, Clone, Default)]
+pub struct Tenant {
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub policy: KeyPolicy,
+    /// Where the budget of the policy is set.
+    pub budget_scope: BudgetScope,
+}
+
+/// Whose usage a budget is checked against: the key's own, or that of all
+/// the keys of the project or organization it is set on.
+#[derive(Debug, Clone, Default, PartialEq, Display)]
+pub enum BudgetScope {
+    #[default]
+    #[display(fmt = "")]
+    Key,
+    #[display(fmt = " of project '{}'", _0)]
+    Project(String),
+    #[display(fmt = " of organization '{}'", _0)]
+    Organization(String),
+}
+
+impl Tenant {
+    pub fn resolve(req: &HttpRequest, config: &Config, key: &ApiKey) -> Result<Self, ApiError> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+        let organization = header(ORGANIZATION_HEADER);
+        let project = header(PROJECT_HEADER);
+
+        let projects: Vec<_> = key
+            .projects
+            .iter()
+            .filter_map(|id| config.project(id))
+            .collect();
+        if projects.is_empty() {
+            return Ok(Tenant {
+                policy: key.policy.clone(),
+                ..Default::default()
+            });
+        }
+
+        if let Some(project) = project {
+            if !projects.iter().any(|(_, p)| p.id == project) {
+                return Err(ApiError::Forbidden(format!(
+                    "API key is not a member of project '{}'",
+                    project
+                )));
+            }
+        }
+        let selected = projects.iter().find(|(o, p)| {
+            organization.is_none_or(|id| o.id == id) && project.is_none_or(|id| p.id == id)
+        });
+        match selected {
+            Some((o, p)) => Ok(Tenant {
+                organization: Some(o.id.clone()),
+                project: Some(p.id.clone()),
+                policy: key.policy.inherit(&p.policy).inherit(&o.policy),
+                budget_scope: match (&key.policy.budget, &p.policy.budget) {
+                    (Some(_), _) => BudgetScope::Key,
+                    (None, Some(_)) => BudgetScope::Project(p.id.clone()),
+                    (None, None) => BudgetScope::Organization(o.id.clone()),
+                },
+            }),
+            None => Err(ApiError::Forbidden(match project {
+                Some(project) => format!(
+                    "Project '{}' does not belong to organization '{}'",
+                    project,
+                    organization.unwrap_or_default()
+                ),
+                None => format!(
+                    "API key has no project in organization '{}'",
+                    organization.unwrap_or_default()
+                ),
+            })),
+        }
+    }
+
+    pub fn check_persona(&self, persona: &str) -> Result<(), ApiError> {
+        match self.policy.allows_persona(persona) {
+            true => Ok(()),
+            false => Err(ApiError::PersonaNotAllowed(format!(
+                "Persona '{}' is not available to this API key",
+                persona
+            ))),
+        }
+    }
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Requests of each caller in fixed one minute windows, kept in memory.
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    /// Counts a request of the caller, unless it is over
+    /// `requests_per_minute`.
+    pub fn check(&self, caller: &str, requests_per_minute: Option<u32>) -> Result<(), ApiError> {
+        let limit = match requests_per_minute {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut windows = self.windows.lock();
+        let (started, requests) = windows.entry(caller.to_string()).or_insert((now, 0));
+        if now.duration_since(*started) >= RATE_WINDOW {
+            *started = now;
+            *requests = 0;
+        }
+        if *requests >= limit {
+            return Err(ApiError::RateLimited(format!(
+                "Rate limit of {} requests per minute reached",
+                limit
+            )));
+        }
+        *requests += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::Secret;
+    use actix_web::test::TestRequest;
+
+    /// `org-acme` with the projects `proj-web` and `proj-api`, and `org-beta`
+    /// with `proj-beta`. The organizations set every policy setting, the
+    /// projects some.
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+llm_backend = "openai"
+llm_api_url = "https://api.openai.com/v1"
+llm_model_name = "gpt-4o"
+api_keys = []
+
+[[organizations]]
+id = "org-acme"
+budget = { monthly = 100.0 }
+personas = ["assistant", "tutor"]
+requests_per_minute = 60
+max_tokens = 4000
+audit_bodies = true
+
+[[organizations.projects]]
+id = "proj-web"
+budget = { monthly = 10.0 }
+requests_per_minute = 30
+
+[[organizations.projects]]
+id = "proj-api"
+max_tokens = 1000
+
+[[organizations]]
+id = "org-beta"
+requests_per_minute = 5
+
+[[organizations.projects]]
+id = "proj-beta"
+"#,
+        )
+        .unwrap()
+    }
+
+    fn key(projects: &[&str], policy: KeyPolicy) -> ApiKey {
+        ApiKey {
+            key: Secret::new("sk-test"),
+            name: "Key".into(),
+            description: None,
+            permissions: vec![],
+            language: None,
+            client_cert: None,
+            projects: projects.iter().map(|p| p.to_string()).collect(),
+            policy,
+            restrictions: Default::default(),
+        }
+    }
+
+    fn resolve(headers: &[(&str, &str)], key: &ApiKey) -> Result<Tenant, ApiError> {
+        let mut req = TestRequest::default();
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        Tenant::resolve(&req.to_http_request(), &config(), key)
+    }
+
+    #[test]
+    fn keys_without_projects_keep_their_own_policy() {
+        let key = key(
+            &[],
+            KeyPolicy {
+                requests_per_minute: Some(7),
+                ..Default::default()
+            },
+        );
+        let tenant = resolve(&[(PROJECT_HEADER, "proj-web")], &key).unwrap();
+        assert_eq!(tenant.organization, None);
+        assert_eq!(tenant.project, None);
+        assert_eq!(tenant.policy.requests_per_minute, Some(7));
+        assert_eq!(tenant.policy.max_tokens, None);
+        assert_eq!(tenant.budget_scope, BudgetScope::Key);
+    }
+
+    #[test]
+    fn policies_are_inherited_from_the_key_then_project_then_organization() {
+        let key = key(
+            &["proj-web"],
+            KeyPolicy {
+                personas: Some(vec!["assistant".into()]),
+                ..Default::default()
+            },
+        );
+        let tenant = resolve(&[], &key).unwrap();
+        assert_eq!(tenant.organization.as_deref(), Some("org-acme"));
+        assert_eq!(tenant.project.as_deref(), Some("proj-web"));
+
+        let policy = &tenant.policy;
+        // the key
+        assert_eq!(policy.personas, Some(vec!["assistant".to_string()]));
+        // the project, over the organization
+        assert_eq!(policy.requests_per_minute, Some(30));
+        assert_eq!(policy.budget.as_ref().map(|b| b.monthly), Some(10.0));
+        // the organization
+        assert_eq!(policy.max_tokens, Some(4000));
+        assert_eq!(policy.audit_bodies, Some(true));
+    }
+
+    #[test]
+    fn budgets_are_shared_where_they_are_set() {
+        let tenant = resolve(&[], &key(&["proj-web"], Default::default())).unwrap();
+        assert_eq!(tenant.budget_scope, BudgetScope::Project("proj-web".into()));
+
+        let tenant = resolve(&[], &key(&["proj-api"], Default::default())).unwrap();
+        assert_eq!(tenant.policy.budget.map(|b| b.monthly), Some(100.0));
+        assert_eq!(
+            tenant.budget_scope,
+            BudgetScope::Organization("org-acme".into())
+        );
+
+        let own = KeyPolicy {
+            budget: toml::from_str("monthly = 1.0").ok(),
+            ..Default::default()
+        };
+        let tenant = resolve(&[], &key(&["proj-web"], own)).unwrap();
+        assert_eq!(tenant.policy.budget.map(|b| b.monthly), Some(1.0));
+        assert_eq!(tenant.budget_scope, BudgetScope::Key);
+    }
+
+    #[test]
+    fn headers_pick_among_the_projects_of_the_key() {
+        let key = key(&["proj-web", "proj-api", "proj-beta"], Default::default());
+        assert_eq!(
+            resolve(&[], &key).unwrap().project.as_deref(),
+            Some("proj-web")
+        );
+
+        let tenant = resolve(&[(PROJECT_HEADER, "proj-api")], &key).unwrap();
+        assert_eq!(tenant.project.as_deref(), Some("proj-api"));
+        assert_eq!(tenant.policy.max_tokens, Some(1000));
+
+        let tenant = resolve(&[(ORGANIZATION_HEADER, "org-beta")], &key).unwrap();
+        assert_eq!(tenant.organization.as_deref(), Some("org-beta"));
+        assert_eq!(tenant.project.as_deref(), Some("proj-beta"));
+        assert_eq!(tenant.policy.requests_per_minute, Some(5));
+    }
+
+    #[test]
+    fn other_projects_and_organizations_are_forbidden() {
+        let key = key(&["proj-web"], Default::default());
+        let forbidden = |headers: &[(&str, &str)]| {
+            matches!(resolve(headers, &key), Err(ApiError::Forbidden(_)))
+        };
+        assert!(forbidden(&[(PROJECT_HEADER, "proj-api")]));
+        assert!(forbidden(&[(ORGANIZATION_HEADER, "org-beta")]));
+        assert!(forbidden(&[
+            (ORGANIZATION_HEADER, "org-beta"),
+            (PROJECT_HEADER, "proj-web")
+        ]));
+        assert!(!forbidden(&[
+            (ORGANIZATION_HEADER, "org-acme"),
+            (PROJECT_HEADER, "proj-web")
+        ]));
+    }
+}
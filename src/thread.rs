@@ -0,0 +1,466 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Server-side conversation threads.
+//!
+//! Threads are stored per API key in the app database, so clients only send
+//! the new message and let the server replay the history on every run.
+
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent, Role};
+use pickledb::PickleDb;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::{Instrument, Span};
+
+use crate::{
+    apitype,
+    appctx::AppContext,
+    auth::Caller,
+    config::Config,
+    endpoint,
+    error::ApiError,
+    llm::{LlmBackend, OpenAiBackend},
+    streamer::{self, StreamWriter},
+    usage,
+};
+
+type OAIAppContext = AppContext<OpenAiBackend>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Thread {
+    pub id: String,
+    /// The object type, which is always "thread".
+    pub object: String,
+    /// The Unix timestamp (in seconds) when the thread was created.
+    pub created_at: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThreadMessage {
+    pub id: String,
+    /// The object type, which is always "thread.message".
+    pub object: String,
+    /// The Unix timestamp (in seconds) when the message was created.
+    pub created_at: u32,
+    pub thread_id: String,
+    /// The entity that produced the message, either "user" or "assistant".
+    pub role: Role,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Run {
+    pub id: String,
+    /// The object type, which is always "thread.run".
+    pub object: String,
+    /// The Unix timestamp (in seconds) when the run was created.
+    pub created_at: u32,
+    pub thread_id: String,
+    /// The persona used for this run.
+    pub model: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<apitype::ChatCompletionUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateThreadRequest {
+    /// Messages to start the thread with.
+    pub messages: Option<Vec<CreateMessageRequest>>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateMessageRequest {
+    pub role: Role,
+    pub content: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateRunRequest {
+    /// The persona to run the thread with.
+    pub model: String,
+    pub stream: Option<bool>,
+    pub user: Option<String>,
+    /// Variables for the persona prompt, added to the thread metadata.
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThreadMessageList {
+    /// The object type, which is always "list".
+    pub object: String,
+    pub data: Vec<ThreadMessage>,
+}
+
+/// A thread and its messages as persisted in the database.
+#[derive(Serialize, Deserialize, Debug)]
+struct StoredThread {
+    /// Id of the caller, see [`Caller::id`].
+    owner: String,
+    thread: Thread,
+    messages: Vec<ThreadMessage>,
+}
+
+impl StoredThread {
+    fn push_message(&mut self, role: Role, content: String) -> ThreadMessage {
+        let message = ThreadMessage {
+            id: generate_id("msg"),
+            object: "thread.message".into(),
+            created_at: now(),
+            thread_id: self.thread.id.clone(),
+            role,
+            content,
+        };
+        self.messages.push(message.clone());
+        message
+    }
+
+    fn chat_messages(&self) -> Vec<ChatMessage> {
+        self.messages
+            .iter()
+            .map(|m| ChatMessage {
+                role: m.role.clone(),
+                content: ChatMessageContent::Text(m.content.clone()),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+fn db_key(thread_id: &str) -> String {
+    format!("threads:{}", thread_id)
+}
+
+/// Moves the threads owned by the token of an API key, as they were before
+/// callers were known by the key name, to that name.
+pub fn migrate_owners(db: &mut PickleDb, config: &Config) {
+    let keys = db
+        .get_all()
+        .into_iter()
+        .filter(|k| k.starts_with("threads:"));
+    for key in keys.collect::<Vec<_>>() {
+        let mut thread = match db.get::<StoredThread>(&key) {
+            Some(thread) => thread,
+            None => continue,
+        };
+        let name = match config.api_key(&thread.owner) {
+            Some(api_key) => api_key.name.clone(),
+            None => continue,
+        };
+        thread.owner = name;
+        if let Err(e) = db.set(&key, &thread) {
+            error!("thread {} not migrated: {}", thread.thread.id, e);
+        }
+    }
+}
+
+fn find_thread(db: &PickleDb, thread_id: &str, owner: &str) -> Result<StoredThread, ApiError> {
+    db.get::<StoredThread>(&db_key(thread_id))
+        // never reveal threads owned by another key
+        .filter(|t| t.owner == owner)
+        .ok_or_else(|| ApiError::NotFound(format!("No thread found with id '{}'", thread_id)))
+}
+
+fn load_thread(
+    ctx: &OAIAppContext,
+    thread_id: &str,
+    owner: &str,
+) -> Result<StoredThread, ApiError> {
+    find_thread(&ctx.db.lock().unwrap(), thread_id, owner)
+}
+
+/// Loads, changes and saves the thread under one lock, so concurrent
+/// changes to the same thread are all kept.
+fn update_thread<R>(
+    ctx: &OAIAppContext,
+    thread_id: &str,
+    owner: &str,
+    update: impl FnOnce(&mut StoredThread) -> R,
+) -> Result<R, ApiError> {
+    let mut db = ctx.db.lock().unwrap();
+    let mut thread = find_thread(&db, thread_id, owner)?;
+    let result = update(&mut thread);
+    db.set(&db_key(thread_id), &thread)
+        .expect("Cannot write thread");
+    Ok(result)
+}
+
+fn save_thread(ctx: &OAIAppContext, thread: &StoredThread) {
+    let mut db = ctx.db.lock().unwrap();
+    db.set(&db_key(&thread.thread.id), thread)
+        .expect("Cannot write thread");
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+pub fn generate_id(prefix: &str) -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+    let code: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    format!("{}_{}", prefix, code)
+}
+
+fn validate_role(role: &Role) -> Result<(), ApiError> {
+    match role {
+        Role::User | Role::Assistant => Ok(()),
+        _ => Err(ApiError::BadRequest(format!(
+            "Invalid role '{}', expected 'user' or 'assistant'",
+            role
+        ))),
+    }
+}
+
+#[post("/threads")]
+pub async fn create_thread(
+    data: web::Json<CreateThreadRequest>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<impl Responder, ApiError> {
+    let data = data.into_inner();
+
+    let mut thread = StoredThread {
+        owner: caller.id,
+        thread: Thread {
+            id: generate_id("thread"),
+            object: "thread".into(),
+            created_at: now(),
+            metadata: data.metadata,
+        },
+        messages: vec![],
+    };
+
+    for message in data.messages.unwrap_or_default() {
+        validate_role(&message.role)?;
+        thread.push_message(message.role, message.content);
+    }
+
+    save_thread(&ctx, &thread);
+
+    Ok(HttpResponse::Ok().json(thread.thread))
+}
+
+#[get("/threads/{thread_id}")]
+pub async fn get_thread(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<impl Responder, ApiError> {
+    let thread = load_thread(&ctx, &path, &caller.id)?;
+    Ok(HttpResponse::Ok().json(thread.thread))
+}
+
+#[delete("/threads/{thread_id}")]
+pub async fn delete_thread(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<impl Responder, ApiError> {
+    let thread = {
+        let mut db = ctx.db.lock().unwrap();
+        let thread = find_thread(&db, &path, &caller.id)?;
+        db.rem(&db_key(&thread.thread.id))
+            .expect("Cannot delete thread");
+        thread
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "id": thread.thread.id,
+        "object": "thread.deleted",
+        "deleted": true,
+    })))
+}
+
+#[post("/threads/{thread_id}/messages")]
+pub async fn create_message(
+    path: web::Path<String>,
+    data: web::Json<CreateMessageRequest>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<impl Responder, ApiError> {
+    let data = data.into_inner();
+    validate_role(&data.role)?;
+
+    let message = update_thread(&ctx, &path, &caller.id, |thread| {
+        thread.push_message(data.role, data.content)
+    })?;
+
+    Ok(HttpResponse::Ok().json(message))
+}
+
+#[get("/threads/{thread_id}/messages")]
+pub async fn list_messages(
+    path: web::Path<String>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<impl Responder, ApiError> {
+    let thread = load_thread(&ctx, &path, &caller.id)?;
+    Ok(HttpResponse::Ok().json(ThreadMessageList {
+        object: "list".into(),
+        data: thread.messages,
+    }))
+}
+
+#[post("/threads/{thread_id}/runs")]
+pub async fn create_run(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Json<CreateRunRequest>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, ApiError> {
+    let data = data.into_inner();
+    if !endpoint::is_model_supported(&data.model, &ctx) {
+        return Err(ApiError::BadRequest("Model not supported".into()));
+    }
+    Span::current().record("persona", data.model.as_str());
+
+    let (tenant, budget_warning) = endpoint::admit(&req, &ctx, &caller, &data.model)?;
+    let caller_id = caller.id.clone();
+    endpoint::track_metric_counter("/threads/runs", &caller_id, &ctx);
+
+    let thread = load_thread(&ctx, &path, &caller_id)?;
+    if thread.messages.is_empty() {
+        return Err(ApiError::BadRequest("Thread has no messages".into()));
+    }
+
+    let mut metadata = thread.thread.metadata.clone().unwrap_or_default();
+    metadata.extend(data.metadata.unwrap_or_default());
+    let mut prompt_ctx = endpoint::prompt_context(&req, &caller, data.user, Some(&metadata));
+    prompt_ctx.tenant = tenant;
+
+    let stream = data.stream == Some(true);
+    let mut audit = ctx
+        .audit
+        .start(req.path(), &prompt_ctx, &data.model, stream);
+
+    // the backend truncates the replayed history to fit the context window
+    let llm_backend = ctx.llm_backend();
+    let mut prompt = match llm_backend
+        .build_prompt(thread.chat_messages(), &data.model, &prompt_ctx)
+        .await
+    {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            audit.error(&e);
+            usage::record(&ctx, &caller_id, &mut audit);
+            ctx.audit.write(&audit);
+            return Err(e);
+        }
+    };
+    prompt.parameters.max_tokens = prompt_ctx.tenant.policy.max_tokens(None);
+    Span::current().record("upstream", prompt.parameters.model.as_str());
+    audit.prompt(&prompt, llm_backend.tokenizer());
+
+    let mut run = Run {
+        id: generate_id("run"),
+        object: "thread.run".into(),
+        created_at: now(),
+        thread_id: thread.thread.id.clone(),
+        model: data.model.clone(),
+        status: "completed".into(),
+        usage: None,
+    };
+
+    let mut response = HttpResponse::Ok();
+    prompt.insert_headers(&mut response);
+    if let Some(warning) = budget_warning {
+        response.insert_header(("x-restoai-budget-warning", warning));
+    }
+
+    if stream {
+        let (backend_tx, backend_rx) = mpsc::channel(10);
+        let (tx, rx) = mpsc::channel(10);
+
+        let tokenizer = llm_backend.tokenizer().clone();
+        tokio::spawn(
+            async move {
+                llm_backend
+                    .submit_prompt_stream(prompt, StreamWriter(Arc::new(backend_tx)))
+                    .await;
+            }
+            .in_current_span(),
+        );
+
+        // store the assistant answer once the stream has been fully relayed
+        let active = ctx
+            .streams
+            .register(req.path(), prompt_ctx.key_name.clone(), &data.model);
+        let ctx = ctx.clone();
+        tokio::spawn(
+            async move {
+                let relayed = streamer::relay(backend_rx, StreamWriter(Arc::new(tx))).await;
+                drop(active);
+                audit.stream(&relayed, &tokenizer);
+                usage::record(&ctx, &caller_id, &mut audit);
+                ctx.audit.write(&audit);
+                let pushed = update_thread(&ctx, &run.thread_id, &caller_id, |thread| {
+                    thread.push_message(Role::Assistant, relayed.content);
+                });
+                if pushed.is_err() {
+                    debug!("thread {} deleted during run {}", run.thread_id, run.id);
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(streamer::event_stream(response, rx))
+    } else {
+        let result = match llm_backend.submit_prompt(prompt).await {
+            Ok(result) => result,
+            Err(e) => {
+                audit.error(&e);
+                usage::record(&ctx, &caller_id, &mut audit);
+                ctx.audit.write(&audit);
+                return Err(e);
+            }
+        };
+        audit.response(&result);
+        if let Some(cost) = usage::record(&ctx, &caller_id, &mut audit) {
+            response.insert_header(("x-restoai-cost", format!("{:.6}", cost)));
+        }
+        ctx.audit.write(&audit);
+
+        let content = result
+            .choices
+            .first()
+            .and_then(|c| match &c.message.content {
+                apitype::ChatMessageContent::Text(text) => Some(text.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        // other messages may have been added while waiting for the backend
+        update_thread(&ctx, &run.thread_id, &caller_id, |thread| {
+            thread.push_message(Role::Assistant, content);
+        })?;
+
+        run.usage = result.usage;
+        Ok(response.json(run))
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! HTTPS with rustls.
+//!
+//! The certificate and key are swapped in place when their files change, so
+//! renewals apply without a restart. With `client_ca` clients may present a
+//! certificate, and one mapped to an API key by its fingerprint
+//! authenticates the requests on that connection as the key.
+
+use actix_tls::accept::rustls_0_22::TlsStream;
+use actix_web::{
+    dev::{Extensions, ServiceRequest},
+    http::header::AUTHORIZATION,
+    rt::net::TcpStream,
+    web, HttpMessage,
+};
+use arc_swap::ArcSwap;
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    any::Any,
+    fmt, fs,
+    io::{self, BufReader},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    appctx::AppContext,
+    auth::Caller,
+    config::{ApiKey, ClientAuth, Config, TlsConfig},
+    llm::OpenAiBackend,
+};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn read_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in `{}`", path)));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid(format!("no private key in `{}`", path)))
+}
+
+fn certified_key(config: &TlsConfig) -> io::Result<CertifiedKey> {
+    let certs = read_certs(&config.cert)?;
+    let key = read_key(&config.key)?;
+    let key = any_supported_type(&key)
+        .map_err(|e| invalid(format!("unsupported key in `{}`: {}", config.key, e)))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Serves the current certificate, see [`reload_on_change`].
+pub struct CertResolver {
+    key: ArcSwap<CertifiedKey>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CertResolver")
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.load_full())
+    }
+}
+
+/// Server TLS config, with its certificate resolver for
+/// [`reload_on_change`].
+pub fn server_config(config: &TlsConfig) -> io::Result<(ServerConfig, Arc<CertResolver>)> {
+    let resolver = Arc::new(CertResolver {
+        key: ArcSwap::from_pointee(certified_key(config)?),
+    });
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| invalid(format!("invalid CA in `{}`: {}", client_ca, e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = match config.client_auth {
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+                ClientAuth::Required => verifier,
+            };
+            let verifier = verifier
+                .build()
+                .map_err(|e| invalid(format!("invalid client CA `{}`: {}", client_ca, e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok((builder.with_cert_resolver(resolver.clone()), resolver))
+}
+
+fn modified(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((modified(&config.cert)?, modified(&config.key)?))
+}
+
+/// Checks the certificate and key files every `reload_interval` seconds and
+/// swaps them in when they changed. Connections already open keep the old
+/// certificate.
+pub async fn reload_on_change(resolver: Arc<CertResolver>, config: TlsConfig) {
+    let mut last = modified(&config);
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(config.reload_interval()));
+    loop {
+        interval.tick().await;
+        let current = modified(&config);
+        if current.is_none() || current == last {
+            continue;
+        }
+        // renewals may write the files one after the other, retry until both
+        // match
+        match certified_key(&config) {
+            Ok(key) => {
+                resolver.key.store(Arc::new(key));
+                last = current;
+                info!("TLS certificate reloaded from {}", config.cert);
+            }
+            Err(e) => warn!("TLS certificate not reloaded: {}", e),
+        }
+    }
+}
+
+/// SHA-256 fingerprint of the client certificate of a connection, lowercase
+/// hex.
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub String);
+
+/// Records the client certificate of TLS connections, for
+/// `HttpServer::on_connect`.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let cert = connection
+        .downcast_ref::<TlsStream<TcpStream>>()
+        .and_then(|stream| stream.get_ref().1.peer_certificates())
+        .and_then(|certs| certs.first());
+    if let Some(cert) = cert {
+        let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+        let fingerprint = digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        data.insert(ClientCert(fingerprint));
+    }
+}
+
+/// The API key the client certificate of the connection is mapped to.
+pub fn client_cert_key<'a>(req: &ServiceRequest, config: &'a Config) -> Option<&'a ApiKey> {
+    let ClientCert(fingerprint) = req.conn_data::<ClientCert>()?;
+    config.api_key_by_cert(fingerprint)
+}
+
+/// Authenticates requests without an `Authorization` header as the API key
+/// their client certificate is mapped to, the bearer validator taking the
+/// [`Caller`] set here.
+pub fn authorize_client_cert(req: &mut ServiceRequest) {
+    if req.headers().contains_key(AUTHORIZATION) {
+        return;
+    }
+    let fingerprint = match req.conn_data::<ClientCert>() {
+        Some(ClientCert(fingerprint)) => fingerprint.clone(),
+        None => return,
+    };
+    let config = match req.app_data::<web::Data<AppContext<OpenAiBackend>>>() {
+        Some(ctx) => ctx.config(),
+        None => return,
+    };
+    match config.api_key_by_cert(&fingerprint) {
+        Some(key) => {
+            debug!("client certificate authenticates as `{}`", key.name);
+            req.extensions_mut().insert(Caller::of_key(key));
+        }
+        None => debug!("client certificate {} is not mapped to a key", fingerprint),
+    }
+}
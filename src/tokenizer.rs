@@ -0,0 +1,347 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Local tokenizer.
+//!
+//! Loads tiktoken-compatible BPE rank files (`<base64 token> <rank>` per line)
+//! from disk. Upstream models without a configured tokenizer fall back to a
+//! character based estimate.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fancy_regex::Regex;
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent};
+use std::{collections::HashMap, fs, sync::Arc};
+
+use crate::config::{Config, TokenizerConfig};
+
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const O200K_PATTERN: &str = r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const P50K_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// Tokens added around every message by the chat format.
+const TOKENS_PER_MESSAGE: u32 = 3;
+/// Tokens priming the assistant reply.
+const TOKENS_PER_REPLY: u32 = 3;
+
+/// Split pattern of the well-known tiktoken encodings.
+fn builtin_pattern(name: &str) -> Option<&'static str> {
+    match name {
+        "cl100k_base" => Some(CL100K_PATTERN),
+        "o200k_base" => Some(O200K_PATTERN),
+        "p50k_base" | "p50k_edit" | "r50k_base" => Some(P50K_PATTERN),
+        _ => None,
+    }
+}
+
+pub struct Bpe {
+    name: String,
+    ranks: HashMap<Vec<u8>, u32>,
+    pattern: Regex,
+}
+
+impl Bpe {
+    pub fn load(config: &TokenizerConfig) -> Result<Self, String> {
+        let pattern = config
+            .pattern
+            .as_deref()
+            .or_else(|| builtin_pattern(&config.name))
+            .ok_or_else(|| format!("tokenizer `{}` needs a `pattern`", config.name))?;
+        let pattern = Regex::new(pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+
+        let data = fs::read_to_string(&config.path)
+            .map_err(|e| format!("cannot read `{}`: {}", config.path, e))?;
+
+        let mut ranks = HashMap::new();
+        for (i, line) in data.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+            let invalid = || format!("{}:{}: invalid tiktoken entry", config.path, i + 1);
+            let (token, rank) = line.split_once(' ').ok_or_else(invalid)?;
+            let token = STANDARD.decode(token).map_err(|_| invalid())?;
+            let rank = rank.trim().parse::<u32>().map_err(|_| invalid())?;
+            ranks.insert(token, rank);
+        }
+
+        debug!(
+            "loaded tokenizer `{}` with {} ranks from {}",
+            config.name,
+            ranks.len(),
+            config.path
+        );
+
+        Ok(Self {
+            name: config.name.clone(),
+            ranks,
+            pattern,
+        })
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.pattern
+            .find_iter(text)
+            .filter_map(Result::ok)
+            .flat_map(|m| self.byte_pair_encode(m.as_str().as_bytes()))
+            .collect()
+    }
+
+    fn byte_pair_encode(&self, piece: &[u8]) -> Vec<u32> {
+        if let Some(rank) = self.ranks.get(piece) {
+            return vec![*rank];
+        }
+
+        // boundaries of the current parts, merge the lowest ranked pair until
+        // no adjacent pair is a known token
+        let mut parts: Vec<usize> = (0..=piece.len()).collect();
+        loop {
+            let lowest = (0..parts.len().saturating_sub(2))
+                .filter_map(|i| {
+                    self.ranks
+                        .get(&piece[parts[i]..parts[i + 2]])
+                        .map(|r| (*r, i))
+                })
+                .min();
+            match lowest {
+                Some((_, i)) => {
+                    parts.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+
+        parts
+            .windows(2)
+            .filter_map(|w| self.ranks.get(&piece[w[0]..w[1]]).copied())
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub enum Tokenizer {
+    Bpe(Arc<Bpe>),
+    /// ~4 characters per token, used when no tokenizer is configured.
+    Estimate,
+}
+
+impl Tokenizer {
+    /// Tokenizer configured for the given upstream model in the model catalog.
+    pub fn from_config(config: &Config, upstream_model: &str) -> Result<Self, String> {
+        let name = match config
+            .upstream_model(upstream_model)
+            .and_then(|m| m.tokenizer.as_ref())
+        {
+            Some(name) => name,
+            None => return Ok(Tokenizer::Estimate),
+        };
+
+        let tokenizer = config
+            .tokenizers
+            .iter()
+            .find(|t| &t.name == name)
+            .ok_or_else(|| format!("Unknown tokenizer `{}` for `{}`", name, upstream_model))?;
+
+        Bpe::load(tokenizer)
+            .map(|bpe| Tokenizer::Bpe(Arc::new(bpe)))
+            .map_err(|e| format!("Cannot load tokenizer `{}`: {}", name, e))
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Tokenizer::Bpe(bpe) => &bpe.name,
+            Tokenizer::Estimate => "estimate",
+        }
+    }
+
+    /// Token ids of `text`, `None` when only estimating.
+    pub fn encode(&self, text: &str) -> Option<Vec<u32>> {
+        match self {
+            Tokenizer::Bpe(bpe) => Some(bpe.encode(text)),
+            Tokenizer::Estimate => None,
+        }
+    }
+
+    pub fn count(&self, text: &str) -> u32 {
+        match self {
+            Tokenizer::Bpe(bpe) => bpe.encode(text).len() as u32,
+            Tokenizer::Estimate => (text.chars().count() / 4) as u32,
+        }
+    }
+
+    /// Tokens used by a single message, including the chat format overhead.
+    pub fn count_message(&self, message: &ChatMessage) -> u32 {
+        match self {
+            Tokenizer::Bpe(_) => {
+                TOKENS_PER_MESSAGE
+                    + self.count(&message.role.to_string())
+                    + self.count(message_text(message))
+                    + message.name.as_deref().map_or(0, |n| self.count(n) + 1)
+            }
+            Tokenizer::Estimate => self.count(message_text(message)) + 4,
+        }
+    }
+
+    /// Tokens used by a whole prompt.
+    pub fn count_messages(&self, messages: &[ChatMessage]) -> u32 {
+        let reply = match self {
+            Tokenizer::Bpe(_) => TOKENS_PER_REPLY,
+            Tokenizer::Estimate => 0,
+        };
+        messages.iter().map(|m| self.count_message(m)).sum::<u32>() + reply
+    }
+}
+
+pub fn message_text(message: &ChatMessage) -> &str {
+    match &message.content {
+        ChatMessageContent::Text(text) => text,
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai_dive::v1::resources::chat::Role;
+
+    /// A BPE with the single bytes of `a` to `e` and space, ranked first, and
+    /// the given merges after them.
+    fn with_merges(merges: &[&str]) -> Bpe {
+        let mut ranks: HashMap<Vec<u8>, u32> = b"abcde "
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (vec![*b], i as u32))
+            .collect();
+        for merge in merges {
+            ranks.insert(merge.as_bytes().to_vec(), ranks.len() as u32);
+        }
+        Bpe {
+            name: "test".into(),
+            ranks,
+            pattern: Regex::new(P50K_PATTERN).unwrap(),
+        }
+    }
+
+    fn tokenizer_file(name: &str, lines: &[String]) -> TokenizerConfig {
+        let path = std::env::temp_dir().join(format!(
+            "restoai-tokenizer-{}-{}.tiktoken",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, lines.join("\n")).unwrap();
+        TokenizerConfig {
+            name: "cl100k_base".into(),
+            path: path.to_string_lossy().into_owned(),
+            pattern: None,
+        }
+    }
+
+    #[test]
+    fn known_piece_is_a_single_token() {
+        let bpe = with_merges(&["ab", "abc"]);
+        assert_eq!(bpe.byte_pair_encode(b"abc"), vec![7]);
+    }
+
+    #[test]
+    fn merges_the_lowest_ranked_pair_first() {
+        // `bc` ranks before `ab`, so `abc` is `a` + `bc`
+        let bpe = with_merges(&["bc", "ab"]);
+        assert_eq!(bpe.byte_pair_encode(b"abc"), vec![0, 6]);
+
+        let bpe = with_merges(&["ab", "bc"]);
+        assert_eq!(bpe.byte_pair_encode(b"abc"), vec![6, 2]);
+    }
+
+    #[test]
+    fn merges_repeatedly() {
+        let bpe = with_merges(&["ab", "cd", "abcd"]);
+        assert_eq!(bpe.byte_pair_encode(b"abcde"), vec![8, 4]);
+    }
+
+    #[test]
+    fn unknown_bytes_are_skipped() {
+        let bpe = with_merges(&[]);
+        assert_eq!(bpe.byte_pair_encode(b"axb"), vec![0, 1]);
+    }
+
+    #[test]
+    fn encode_splits_on_the_pattern() {
+        let bpe = with_merges(&["ab", " c", " cd"]);
+        // pieces `ab` and ` cd`
+        assert_eq!(bpe.encode("ab cd"), vec![6, 8]);
+        assert!(bpe.encode("").is_empty());
+    }
+
+    #[test]
+    fn loads_tiktoken_files() {
+        let lines: Vec<String> = ["a", "b", "ab"]
+            .iter()
+            .enumerate()
+            .map(|(rank, token)| format!("{} {}", STANDARD.encode(token), rank))
+            .collect();
+        let config = tokenizer_file("valid", &lines);
+        let bpe = Bpe::load(&config).unwrap();
+        fs::remove_file(&config.path).unwrap();
+
+        assert_eq!(bpe.ranks.len(), 3);
+        assert_eq!(bpe.encode("ab"), vec![2]);
+        assert_eq!(bpe.encode("ba"), vec![1, 0]);
+    }
+
+    #[test]
+    fn rejects_invalid_tiktoken_entries() {
+        let lines = vec![format!("{} 0", STANDARD.encode("a")), "YQ== x".to_string()];
+        let config = tokenizer_file("invalid", &lines);
+        let error = Bpe::load(&config).err().unwrap();
+        fs::remove_file(&config.path).unwrap();
+        assert!(error.ends_with(":2: invalid tiktoken entry"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_encodings_need_a_pattern() {
+        let config = TokenizerConfig {
+            name: "custom".into(),
+            path: "unused".into(),
+            pattern: None,
+        };
+        assert!(Bpe::load(&config)
+            .err()
+            .unwrap()
+            .contains("needs a `pattern`"));
+    }
+
+    #[test]
+    fn estimate_counts_characters() {
+        let tokenizer = Tokenizer::Estimate;
+        assert_eq!(tokenizer.count("abcdefgh"), 2);
+        // characters, not bytes
+        assert_eq!(tokenizer.count("ééééé"), 1);
+        assert_eq!(tokenizer.encode("abcd"), None);
+    }
+
+    #[test]
+    fn counts_the_chat_format_overhead() {
+        let tokenizer = Tokenizer::Bpe(Arc::new(with_merges(&["ab"])));
+        let message = ChatMessage {
+            role: Role::User,
+            content: ChatMessageContent::Text("ab".into()),
+            ..Default::default()
+        };
+        let role = tokenizer.count(&Role::User.to_string());
+        assert_eq!(
+            tokenizer.count_message(&message),
+            TOKENS_PER_MESSAGE + role + 1
+        );
+        assert_eq!(
+            tokenizer.count_messages(&[message.clone(), message]),
+            2 * (TOKENS_PER_MESSAGE + role + 1) + TOKENS_PER_REPLY
+        );
+    }
+}
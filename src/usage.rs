@@ -0,0 +1,625 @@
+// Copyright (C) 2024 Neuversity
+// All Rights Reserved.
+//
+// NOTICE: All information contained herein is, and remains
+// the property of Neuversity.
+// The intellectual and technical concepts contained
+// herein are proprietary to Neuversity
+// and are protected by trade secret or copyright law.
+// Dissemination of this information or reproduction of this material
+// is strictly forbidden unless prior written permission is obtained
+// from Neuversity.
+
+//! Usage of each API key, per day and persona.
+//!
+//! Recorded when a completion finishes, from the token accounting of its
+//! audit entry, under `usage:<caller id>` in the app database next to the
+//! hit counters, the caller id of an API key being its name. The cost of
+//! each request is priced when it is recorded, so later price changes leave
+//! it as it was. A key holder reads only their own usage at `GET /usage`,
+//! and the monthly costs count against the budget of the key.
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use pickledb::PickleDb;
+use std::collections::BTreeMap;
+
+use crate::{
+    appctx::AppContext,
+    audit::AuditEntry,
+    auth::Caller,
+    config::{Budget, BudgetAction, Config},
+    error::ApiError,
+    llm::OpenAiBackend,
+    tenant::{BudgetScope, Tenant},
+};
+
+type OAIAppContext = AppContext<OpenAiBackend>;
+
+const DEFAULT_DAYS: u32 = 30;
+const MAX_DAYS: u32 = 366;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UsageRecord {
+    date: NaiveDate,
+    persona: String,
+    /// Upstream model the persona was served by, which sets the price.
+    model: Option<String>,
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// USD, unset for usage recorded before costs were.
+    #[serde(default)]
+    cost: Option<f64>,
+}
+
+impl UsageRecord {
+    fn cost(&self, config: &Config) -> f64 {
+        self.cost
+            .or_else(|| {
+                let model = self.model.as_deref()?;
+                config.cost(model, self.prompt_tokens, self.completion_tokens)
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn db_key(caller_id: &str) -> String {
+    format!("usage:{}", caller_id)
+}
+
+fn project_db_key(project: &str) -> String {
+    format!("project_usage:{}", project)
+}
+
+fn organization_db_key(organization: &str) -> String {
+    format!("organization_usage:{}", organization)
+}
+
+/// Usage the budget of a tenant is checked against.
+fn budget_db_key(caller_id: &str, tenant: &Tenant) -> String {
+    match &tenant.budget_scope {
+        BudgetScope::Key => db_key(caller_id),
+        BudgetScope::Project(id) => project_db_key(id),
+        BudgetScope::Organization(id) => organization_db_key(id),
+    }
+}
+
+/// Adds `record` to the one of the same day, persona and model.
+fn add(records: &mut Vec<UsageRecord>, record: UsageRecord, config: &Config) {
+    let existing = records
+        .iter_mut()
+        .find(|r| r.date == record.date && r.persona == record.persona && r.model == record.model);
+    match existing {
+        Some(r) => {
+            r.cost = Some(r.cost(config) + record.cost(config));
+            r.requests += record.requests;
+            r.prompt_tokens += record.prompt_tokens;
+            r.completion_tokens += record.completion_tokens;
+        }
+        None => records.push(record),
+    }
+}
+
+/// Moves the usage recorded under the token of an API key, as it was before
+/// callers were known by the key name, to that name.
+pub fn migrate(db: &mut PickleDb, config: &Config) {
+    for key in &config.api_keys {
+        let old = db_key(key.key.expose());
+        let records = match db.get::<Vec<UsageRecord>>(&old) {
+            Some(records) => records,
+            None => continue,
+        };
+        let new = db_key(&key.name);
+        let mut merged = db.get::<Vec<UsageRecord>>(&new).unwrap_or_default();
+        for record in records {
+            add(&mut merged, record, config);
+        }
+        match db.set(&new, &merged) {
+            Ok(()) => {
+                if let Err(e) = db.rem(&old) {
+                    error!("usage of `{}` kept under its token: {}", key.name, e);
+                }
+            }
+            Err(e) => error!("usage of `{}` not migrated: {}", key.name, e),
+        }
+    }
+}
+
+/// Adds a finished request to the usage of the key, failed ones count as
+/// requests without tokens. Its cost is set on the audit entry, and returned
+/// when its model is priced.
+pub fn record(ctx: &OAIAppContext, caller_id: &str, entry: &mut AuditEntry) -> Option<f64> {
+    let config = ctx.config();
+    record_in(&mut ctx.db.lock().unwrap(), &config, caller_id, entry)
+}
+
+fn record_in(
+    db: &mut PickleDb,
+    config: &Config,
+    caller_id: &str,
+    entry: &mut AuditEntry,
+) -> Option<f64> {
+    let (prompt_tokens, completion_tokens) = entry
+        .usage
+        .as_ref()
+        .map(|u| {
+            (
+                u.prompt_tokens as u64,
+                u.completion_tokens.unwrap_or_default() as u64,
+            )
+        })
+        .unwrap_or_default();
+    let cost = entry
+        .upstream_model
+        .as_deref()
+        .and_then(|m| config.cost(m, prompt_tokens, completion_tokens));
+    entry.cost = cost;
+    let record = UsageRecord {
+        date: entry.started_at.date_naive(),
+        persona: entry.persona.clone(),
+        model: entry.upstream_model.clone(),
+        requests: 1,
+        prompt_tokens,
+        completion_tokens,
+        cost: Some(cost.unwrap_or_default()),
+    };
+
+    // and for the budgets shared by the project and organization
+    let keys = std::iter::once(db_key(caller_id))
+        .chain(entry.project.as_deref().map(project_db_key))
+        .chain(entry.organization.as_deref().map(organization_db_key));
+    for key in keys {
+        let mut records = db.get::<Vec<UsageRecord>>(&key).unwrap_or_default();
+        add(&mut records, record.clone(), config);
+        if let Err(e) = db.set(&key, &records) {
+            error!("usage not recorded: {}", e);
+        }
+    }
+    cost
+}
+
+fn first_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+/// Cost of the requests recorded under `key` in the month of `today`.
+fn spent_this_month(db: &PickleDb, key: &str, config: &Config, today: NaiveDate) -> f64 {
+    let since = first_of_month(today);
+    db.get::<Vec<UsageRecord>>(key)
+        .unwrap_or_default()
+        .iter()
+        .filter(|r| r.date >= since)
+        .map(|r| r.cost(config))
+        .sum()
+}
+
+/// Checks the budget the key gets from its tenant before a request, against
+/// the usage of all the keys of the project or organization when it is set
+/// there. A spent budget rejects the request, or gives the warning to pass
+/// on with `warn`.
+pub fn check_budget(
+    ctx: &OAIAppContext,
+    caller_id: &str,
+    key_name: &str,
+    tenant: &Tenant,
+) -> Result<Option<String>, ApiError> {
+    let config = ctx.config();
+    let today = Utc::now().date_naive();
+    check_budget_in(
+        &ctx.db.lock().unwrap(),
+        &config,
+        caller_id,
+        key_name,
+        tenant,
+        today,
+    )
+}
+
+fn check_budget_in(
+    db: &PickleDb,
+    config: &Config,
+    caller_id: &str,
+    key_name: &str,
+    tenant: &Tenant,
+    today: NaiveDate,
+) -> Result<Option<String>, ApiError> {
+    let budget = match &tenant.policy.budget {
+        Some(budget) => budget,
+        None => return Ok(None),
+    };
+    let spent = spent_this_month(db, &budget_db_key(caller_id, tenant), config, today);
+    if spent < budget.monthly {
+        return Ok(None);
+    }
+
+    let message = format!(
+        "Monthly budget{} of {:.2} USD spent ({:.2} USD this month)",
+        tenant.budget_scope, budget.monthly, spent
+    );
+    match budget.action {
+        BudgetAction::Block => Err(ApiError::QuotaExceeded(message)),
+        BudgetAction::Warn => {
+            warn!("API key `{}`: {}", key_name, message);
+            Ok(Some(message))
+        }
+    }
+}
+
+fn round_cost<S: serde::Serializer>(cost: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64((cost * 1e6).round() / 1e6)
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Totals {
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+    /// At the prices when the requests were made, unpriced models count as
+    /// free.
+    #[serde(serialize_with = "round_cost")]
+    cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, record: &UsageRecord, config: &Config) {
+        self.requests += record.requests;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.total_tokens += record.prompt_tokens + record.completion_tokens;
+        self.cost += record.cost(config);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DayUsage {
+    date: NaiveDate,
+    #[serde(flatten)]
+    usage: Totals,
+}
+
+#[derive(Debug, Serialize)]
+struct PersonaUsage {
+    persona: String,
+    #[serde(flatten)]
+    usage: Totals,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageReport {
+    object: &'static str,
+    key_name: Option<String>,
+    currency: &'static str,
+    /// First day of the report, the last one is today (UTC).
+    since: NaiveDate,
+    total: Totals,
+    days: Vec<DayUsage>,
+    personas: Vec<PersonaUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget: Option<BudgetUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct BudgetUsage {
+    #[serde(flatten)]
+    budget: Budget,
+    /// Cost of this month so far.
+    #[serde(serialize_with = "round_cost")]
+    spent: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// Number of days up to today, 30 by default.
+    days: Option<u32>,
+}
+
+#[get("/usage")]
+pub async fn usage(
+    req: HttpRequest,
+    query: web::Query<UsageQuery>,
+    ctx: web::Data<OAIAppContext>,
+    caller: Caller,
+) -> Result<HttpResponse, ApiError> {
+    let days = query.days.unwrap_or(DEFAULT_DAYS).clamp(1, MAX_DAYS);
+    let since = Utc::now().date_naive() - Duration::days(days as i64 - 1);
+
+    let records = ctx
+        .db
+        .lock()
+        .unwrap()
+        .get::<Vec<UsageRecord>>(&db_key(&caller.id))
+        .unwrap_or_default();
+
+    let config = ctx.config();
+    let mut total = Totals::default();
+    let mut by_day = BTreeMap::<NaiveDate, Totals>::new();
+    let mut by_persona = BTreeMap::<String, Totals>::new();
+    for record in records.iter().filter(|r| r.date >= since) {
+        total.add(record, &config);
+        by_day.entry(record.date).or_default().add(record, &config);
+        by_persona
+            .entry(record.persona.clone())
+            .or_default()
+            .add(record, &config);
+    }
+
+    let tenant = Tenant::resolve(&req, &config, &caller.key)?;
+    let budget = tenant.policy.budget.clone().map(|budget| BudgetUsage {
+        budget,
+        spent: spent_this_month(
+            &ctx.db.lock().unwrap(),
+            &budget_db_key(&caller.id, &tenant),
+            &config,
+            Utc::now().date_naive(),
+        ),
+    });
+
+    Ok(HttpResponse::Ok().json(UsageReport {
+        object: "usage",
+        key_name: Some(caller.key.name.clone()),
+        currency: "USD",
+        since,
+        total,
+        days: by_day
+            .into_iter()
+            .map(|(date, usage)| DayUsage { date, usage })
+            .collect(),
+        personas: by_persona
+            .into_iter()
+            .map(|(persona, usage)| PersonaUsage { persona, usage })
+            .collect(),
+        budget,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apitype::ChatCompletionUsage,
+        audit::AuditLog,
+        config::{AuditConfig, KeyPolicy},
+        llm::PromptContext,
+    };
+    use chrono::{TimeZone, Utc};
+    use pickledb::PickleDbDumpPolicy;
+
+    /// `gpt-4o` at 0.005 USD per 1K prompt tokens and 0.015 per 1K
+    /// completion tokens, `local` without prices.
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+llm_backend = "openai"
+llm_api_url = "https://api.openai.com/v1"
+llm_model_name = "gpt-4o"
+api_keys = []
+
+[[models]]
+name = "gpt-4o"
+input_price = 0.005
+output_price = 0.015
+
+[[models]]
+name = "local"
+"#,
+        )
+        .unwrap()
+    }
+
+    /// A database kept in memory only.
+    fn db(name: &str) -> PickleDb {
+        let path = std::env::temp_dir().join(format!("restoai-usage-{}.db", name));
+        PickleDb::new_json(path, PickleDbDumpPolicy::NeverDump)
+    }
+
+    /// Entry of a request on `day` of 1000 prompt and 2000 completion
+    /// tokens, 0.035 USD on `gpt-4o`.
+    fn entry(tenant: &Tenant, model: &str, day: NaiveDate) -> AuditEntry {
+        let prompt_ctx = PromptContext {
+            tenant: tenant.clone(),
+            ..Default::default()
+        };
+        let mut entry = AuditLog::new(&AuditConfig::default()).unwrap().start(
+            "/v1/chat/completions",
+            &prompt_ctx,
+            "assistant",
+            false,
+        );
+        entry.started_at = Utc.from_utc_datetime(&day.and_hms_opt(12, 0, 0).unwrap());
+        entry.upstream_model = Some(model.to_string());
+        entry.usage = Some(ChatCompletionUsage {
+            prompt_tokens: 1000,
+            completion_tokens: Some(2000),
+            total_tokens: 3000,
+        });
+        entry
+    }
+
+    fn day(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    fn budget(monthly: f64, action: BudgetAction) -> Option<Budget> {
+        Some(Budget { monthly, action })
+    }
+
+    fn assert_cost(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "cost {} instead of {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn requests_are_priced_per_model() {
+        let config = config();
+        assert_cost(config.cost("gpt-4o", 1000, 2000).unwrap(), 0.035);
+        assert_cost(config.cost("gpt-4o", 1, 0).unwrap(), 0.000005);
+        assert_eq!(config.cost("local", 1000, 2000), None);
+        assert_eq!(config.cost("unknown", 1000, 2000), None);
+
+        let mut db = db("priced");
+        let tenant = Tenant::default();
+        let mut priced = entry(&tenant, "gpt-4o", day(2, 1));
+        assert_cost(
+            record_in(&mut db, &config, "Key", &mut priced).unwrap(),
+            0.035,
+        );
+        assert_cost(priced.cost.unwrap(), 0.035);
+
+        let mut unpriced = entry(&tenant, "local", day(2, 1));
+        assert_eq!(record_in(&mut db, &config, "Key", &mut unpriced), None);
+        assert_eq!(unpriced.cost, None);
+        assert_cost(
+            spent_this_month(&db, &db_key("Key"), &config, day(2, 1)),
+            0.035,
+        );
+    }
+
+    #[test]
+    fn recorded_costs_keep_the_prices_of_the_day() {
+        let mut config = config();
+        let mut db = db("price-change");
+        let mut entry = entry(&Tenant::default(), "gpt-4o", day(2, 1));
+        record_in(&mut db, &config, "Key", &mut entry);
+
+        config.models[0].input_price = Some(1.0);
+        assert_cost(
+            spent_this_month(&db, &db_key("Key"), &config, day(2, 1)),
+            0.035,
+        );
+    }
+
+    #[test]
+    fn costs_are_reported_to_the_millionth_of_a_dollar() {
+        let totals = Totals {
+            cost: 0.123_456_789,
+            ..Default::default()
+        };
+        let report = serde_json::to_value(&totals).unwrap();
+        assert_eq!(report["cost"], 0.123457);
+    }
+
+    #[test]
+    fn spending_starts_over_each_month() {
+        let config = config();
+        let mut db = db("rollover");
+        let tenant = Tenant::default();
+        let key = db_key("Key");
+        record_in(
+            &mut db,
+            &config,
+            "Key",
+            &mut entry(&tenant, "gpt-4o", day(1, 31)),
+        );
+        assert_cost(spent_this_month(&db, &key, &config, day(1, 31)), 0.035);
+
+        for date in [day(2, 1), day(2, 29)] {
+            record_in(&mut db, &config, "Key", &mut entry(&tenant, "gpt-4o", date));
+        }
+        assert_cost(spent_this_month(&db, &key, &config, day(2, 29)), 0.07);
+        assert_cost(spent_this_month(&db, &key, &config, day(3, 1)), 0.0);
+    }
+
+    #[test]
+    fn a_spent_key_budget_blocks_or_warns() {
+        let config = config();
+        let mut db = db("key-budget");
+        let mut tenant = Tenant {
+            policy: KeyPolicy {
+                budget: budget(0.05, BudgetAction::Block),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let check = |db: &PickleDb, tenant: &Tenant| {
+            check_budget_in(db, &config, "Key", "Key", tenant, day(2, 15))
+        };
+
+        record_in(
+            &mut db,
+            &config,
+            "Key",
+            &mut entry(&tenant, "gpt-4o", day(2, 1)),
+        );
+        assert_eq!(check(&db, &tenant).unwrap(), None);
+
+        record_in(
+            &mut db,
+            &config,
+            "Key",
+            &mut entry(&tenant, "gpt-4o", day(2, 2)),
+        );
+        match check(&db, &tenant) {
+            Err(ApiError::QuotaExceeded(message)) => assert_eq!(
+                message,
+                "Monthly budget of 0.05 USD spent (0.07 USD this month)"
+            ),
+            other => panic!("budget not enforced: {:?}", other),
+        }
+
+        tenant.policy.budget = budget(0.05, BudgetAction::Warn);
+        assert!(check(&db, &tenant).unwrap().is_some());
+        // the usage of other keys does not count
+        assert_eq!(
+            check_budget_in(&db, &config, "Other", "Other", &tenant, day(2, 15)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn an_organization_budget_counts_the_usage_of_all_its_keys() {
+        let config = config();
+        let mut db = db("organization-budget");
+        let tenant = Tenant {
+            organization: Some("org-acme".into()),
+            project: Some("proj-web".into()),
+            policy: KeyPolicy {
+                budget: budget(0.05, BudgetAction::Block),
+                ..Default::default()
+            },
+            budget_scope: BudgetScope::Organization("org-acme".into()),
+        };
+        let check = |db: &PickleDb, caller_id: &str| {
+            check_budget_in(db, &config, caller_id, caller_id, &tenant, day(2, 15))
+        };
+
+        record_in(
+            &mut db,
+            &config,
+            "Alice",
+            &mut entry(&tenant, "gpt-4o", day(2, 1)),
+        );
+        assert_eq!(check(&db, "Alice").unwrap(), None);
+        record_in(
+            &mut db,
+            &config,
+            "Bob",
+            &mut entry(&tenant, "gpt-4o", day(2, 1)),
+        );
+
+        // neither key spent the budget on its own
+        assert_cost(
+            spent_this_month(&db, &db_key("Bob"), &config, day(2, 15)),
+            0.035,
+        );
+        for caller_id in ["Alice", "Bob", "Carol"] {
+            match check(&db, caller_id) {
+                Err(ApiError::QuotaExceeded(message)) => assert_eq!(
+                    message,
+                    "Monthly budget of organization 'org-acme' of 0.05 USD spent \
+                     (0.07 USD this month)"
+                ),
+                other => panic!("budget of `{}` not enforced: {:?}", caller_id, other),
+            }
+        }
+        assert_cost(
+            spent_this_month(&db, &project_db_key("proj-web"), &config, day(2, 15)),
+            0.07,
+        );
+    }
+}
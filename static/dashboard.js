@@ -0,0 +1,110 @@
+"use strict";
+
+// Usage of the API key entered on the page, from `GET /usage`. The key is
+// shared with the playground for the browser session.
+(() => {
+  const $ = (id) => document.getElementById(id);
+
+  const numbers = new Intl.NumberFormat();
+
+  function formatCost(cost, currency) {
+    return new Intl.NumberFormat(undefined, {
+      style: "currency",
+      currency,
+      maximumFractionDigits: cost < 1 ? 4 : 2,
+    }).format(cost);
+  }
+
+  function setStatus(text, isError) {
+    $("status").textContent = text || "";
+    $("status").classList.toggle("error", !!isError);
+  }
+
+  function cell(text) {
+    const td = document.createElement("td");
+    td.textContent = text;
+    return td;
+  }
+
+  // a bar relative to the busiest row, by total tokens
+  function bar(value, max) {
+    const td = document.createElement("td");
+    const bar = document.createElement("span");
+    bar.className = "bar";
+    bar.style.width = `${max ? (100 * value) / max : 0}%`;
+    td.append(bar);
+    return td;
+  }
+
+  function fillTable(id, rows, label, currency) {
+    const max = Math.max(0, ...rows.map((row) => row.total_tokens));
+    $(id).replaceChildren(
+      ...rows.map((row) => {
+        const tr = document.createElement("tr");
+        tr.append(
+          cell(label(row)),
+          cell(numbers.format(row.requests)),
+          cell(numbers.format(row.prompt_tokens)),
+          cell(numbers.format(row.completion_tokens)),
+          cell(formatCost(row.cost, currency)),
+          bar(row.total_tokens, max)
+        );
+        return tr;
+      })
+    );
+  }
+
+  async function show() {
+    const key = $("api-key").value.trim();
+    setStatus("Loading...");
+    const response = await fetch(`/usage?days=${$("days").value}`, {
+      headers: { Authorization: `Bearer ${key}` },
+    });
+    if (!response.ok) {
+      const text = await response.text();
+      let message = text || `${response.status} ${response.statusText}`;
+      try {
+        message = JSON.parse(text).error.message;
+      } catch (_) {
+        // not an API error
+      }
+      throw new Error(message);
+    }
+    const usage = await response.json();
+    sessionStorage.setItem("restoai.key", key);
+
+    $("total-requests").textContent = numbers.format(usage.total.requests);
+    $("total-prompt").textContent = numbers.format(usage.total.prompt_tokens);
+    $("total-completion").textContent = numbers.format(usage.total.completion_tokens);
+    $("total-cost").textContent = formatCost(usage.total.cost, usage.currency);
+    $("budget").hidden = !usage.budget;
+    if (usage.budget) {
+      const { spent, monthly, action } = usage.budget;
+      $("budget-spent").textContent =
+        `${formatCost(spent, usage.currency)} / ${formatCost(monthly, usage.currency)}`;
+      $("budget").classList.toggle("exceeded", spent >= monthly);
+      $("budget").title = action === "warn" ? "Requests are served past the budget" : "Requests are rejected past the budget";
+    }
+
+    // most recent day first
+    fillTable("days-table", [...usage.days].reverse(), (day) => day.date, usage.currency);
+    fillTable("personas-table", usage.personas, (persona) => persona.persona, usage.currency);
+
+    setStatus(`${usage.key_name || "This key"}, since ${usage.since} (UTC).`);
+  }
+
+  $("connect").addEventListener("submit", (event) => {
+    event.preventDefault();
+    show().catch((e) => setStatus(e.message, true));
+  });
+  $("days").addEventListener("change", () => {
+    if ($("api-key").value) {
+      show().catch((e) => setStatus(e.message, true));
+    }
+  });
+
+  $("api-key").value = sessionStorage.getItem("restoai.key") || "";
+  if ($("api-key").value) {
+    show().catch((e) => setStatus(e.message, true));
+  }
+})();
@@ -0,0 +1,387 @@
+"use strict";
+
+// The playground talks to the same API as any client, with the API key
+// entered on the page. Everything is rendered locally, the page loads no
+// third party resources.
+(() => {
+  const $ = (id) => document.getElementById(id);
+
+  const state = {
+    key: sessionStorage.getItem("restoai.key") || "",
+    messages: [],
+    sessionTokens: 0,
+    busy: false,
+  };
+
+  // -- rendering --------------------------------------------------------
+
+  const ESCAPES = { "&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;", "'": "&#39;" };
+
+  function escapeHtml(text) {
+    return text.replace(/[&<>"']/g, (c) => ESCAPES[c]);
+  }
+
+  const KEYWORDS = new Set(
+    ("as async await break case catch class const continue def default del do elif else " +
+      "enum except export extends false fn for from func function go if impl import in " +
+      "interface let loop match mod mut new nil none null pass pub raise return self " +
+      "static struct super switch this throw trait true try type use var while with yield")
+      .split(" ")
+  );
+
+  const HASH_COMMENTS = /^(py|python|sh|bash|shell|zsh|console|yaml|yml|toml|ini|conf|rb|ruby|r|perl|dockerfile|makefile)$/;
+
+  function tokenPattern(lang) {
+    const comment = HASH_COMMENTS.test(lang) ? "#[^\\n]*" : "\\/\\/[^\\n]*|\\/\\*[\\s\\S]*?\\*\\/";
+    return new RegExp(
+      `(${comment})|("(?:\\\\.|[^"\\\\])*"|'(?:\\\\.|[^'\\\\\\n])*'|\`(?:\\\\.|[^\`\\\\])*\`)` +
+        "|(\\b\\d+(?:\\.\\d+)?\\b)|([A-Za-z_]\\w*)",
+      "g"
+    );
+  }
+
+  // Good enough for chat answers: comments, strings, numbers and the
+  // keywords of the usual languages.
+  function highlight(code, lang) {
+    const pattern = tokenPattern(lang);
+    let html = "";
+    let last = 0;
+    let match;
+    while ((match = pattern.exec(code))) {
+      html += escapeHtml(code.slice(last, match.index));
+      const kind = match[1] ? "com" : match[2] ? "str" : match[3] ? "num" : KEYWORDS.has(match[4]) ? "kw" : null;
+      html += kind ? `<span class="tok-${kind}">${escapeHtml(match[0])}</span>` : escapeHtml(match[0]);
+      last = pattern.lastIndex;
+    }
+    return html + escapeHtml(code.slice(last));
+  }
+
+  function inline(text) {
+    return text
+      .split(/(`[^`\n]+`)/)
+      .map((part, i) =>
+        i % 2
+          ? `<code>${escapeHtml(part.slice(1, -1))}</code>`
+          : escapeHtml(part)
+              .replace(/\*\*(.+?)\*\*/g, "<strong>$1</strong>")
+              .replace(/(^|[^*])\*([^*\n]+)\*/g, "$1<em>$2</em>")
+              .replace(
+                /\[([^\]]+)\]\((https?:\/\/[^\s)]+)\)/g,
+                '<a href="$2" target="_blank" rel="noopener noreferrer">$1</a>'
+              )
+      )
+      .join("");
+  }
+
+  const FENCE = /^\s*(```|~~~)\s*([\w+#.-]*)/;
+  const LIST_ITEM = /^\s*([-*+]|\d+[.)])\s+/;
+  const BLOCK_START = /^\s*(```|~~~|#{1,6}\s|>|[-*+]\s|\d+[.)]\s)/;
+
+  // The subset of Markdown models answer with. Input is escaped before any
+  // markup is added, so the answer cannot inject HTML.
+  function renderMarkdown(text) {
+    const lines = text.split("\n");
+    const html = [];
+    let i = 0;
+    while (i < lines.length) {
+      const line = lines[i];
+      const fence = line.match(FENCE);
+      if (fence) {
+        const code = [];
+        i++;
+        // a streamed answer may not have the closing fence yet
+        while (i < lines.length && !lines[i].trim().startsWith(fence[1])) {
+          code.push(lines[i++]);
+        }
+        i++;
+        html.push(`<pre><code>${highlight(code.join("\n"), fence[2].toLowerCase())}</code></pre>`);
+        continue;
+      }
+      if (!line.trim()) {
+        i++;
+        continue;
+      }
+      const heading = line.match(/^(#{1,6})\s+(.*)$/);
+      if (heading) {
+        const level = heading[1].length;
+        html.push(`<h${level}>${inline(heading[2])}</h${level}>`);
+        i++;
+        continue;
+      }
+      if (/^\s*>/.test(line)) {
+        const quote = [];
+        while (i < lines.length && /^\s*>/.test(lines[i])) {
+          quote.push(lines[i++].replace(/^\s*>\s?/, ""));
+        }
+        html.push(`<blockquote>${renderMarkdown(quote.join("\n"))}</blockquote>`);
+        continue;
+      }
+      if (LIST_ITEM.test(line)) {
+        const ordered = /^\s*\d/.test(line);
+        const tag = ordered ? "ol" : "ul";
+        const items = [];
+        while (i < lines.length && LIST_ITEM.test(lines[i]) && /^\s*\d/.test(lines[i]) === ordered) {
+          items.push(`<li>${inline(lines[i++].replace(LIST_ITEM, ""))}</li>`);
+        }
+        html.push(`<${tag}>${items.join("")}</${tag}>`);
+        continue;
+      }
+      const paragraph = [];
+      do {
+        paragraph.push(lines[i++]);
+      } while (i < lines.length && lines[i].trim() && !BLOCK_START.test(lines[i]));
+      html.push(`<p>${inline(paragraph.join("\n")).replace(/\n/g, "<br>")}</p>`);
+    }
+    return html.join("");
+  }
+
+  function addMessage(role, content) {
+    const view = document.createElement("div");
+    view.className = `message ${role}`;
+    const label = document.createElement("span");
+    label.className = "role";
+    label.textContent = role;
+    const body = document.createElement("div");
+    view.append(label, body);
+    $("messages").append(view);
+    setContent(view, role, content);
+    return view;
+  }
+
+  function setContent(view, role, content) {
+    const messages = $("messages");
+    const following = messages.scrollHeight - messages.scrollTop - messages.clientHeight < 40;
+    const body = view.lastChild;
+    if (role === "assistant") {
+      body.innerHTML = renderMarkdown(content);
+    } else {
+      body.textContent = content;
+    }
+    if (following) {
+      messages.scrollTop = messages.scrollHeight;
+    }
+  }
+
+  function setStatus(text, isError) {
+    $("status").textContent = text || "";
+    $("status").classList.toggle("error", !!isError);
+  }
+
+  function showUsage(usage, estimated) {
+    const mark = estimated ? "~" : "";
+    $("usage-prompt").textContent = mark + usage.prompt_tokens;
+    $("usage-completion").textContent = mark + usage.completion_tokens;
+    $("usage-total").textContent = mark + usage.total_tokens;
+    state.sessionTokens += usage.total_tokens;
+    $("usage-session").textContent = mark + state.sessionTokens;
+  }
+
+  function resetUsage() {
+    for (const id of ["usage-prompt", "usage-completion", "usage-total"]) {
+      $(id).textContent = "-";
+    }
+    state.sessionTokens = 0;
+    $("usage-session").textContent = "0";
+  }
+
+  function setBusy(busy) {
+    state.busy = busy;
+    $("send").disabled = busy;
+    $("prompt").disabled = busy;
+    if (!busy) {
+      $("prompt").focus();
+    }
+  }
+
+  // -- API --------------------------------------------------------------
+
+  async function errorMessage(response) {
+    const text = await response.text();
+    try {
+      return JSON.parse(text).error.message;
+    } catch (_) {
+      return text || `${response.status} ${response.statusText}`;
+    }
+  }
+
+  async function api(path, body) {
+    const headers = { Authorization: `Bearer ${state.key}` };
+    if (body) {
+      headers["Content-Type"] = "application/json";
+    }
+    const response = await fetch(path, {
+      method: body ? "POST" : "GET",
+      headers,
+      body: body && JSON.stringify(body),
+    });
+    if (!response.ok) {
+      throw new Error(await errorMessage(response));
+    }
+    return response;
+  }
+
+  async function connect() {
+    setStatus("Connecting...");
+    const models = await (await api("/models")).json();
+    const select = $("persona");
+    const current = select.value;
+    select.replaceChildren(
+      ...models.data.map((model) => {
+        const option = document.createElement("option");
+        option.value = option.textContent = model.id;
+        return option;
+      })
+    );
+    if (models.data.some((model) => model.id === current)) {
+      select.value = current;
+    }
+    select.disabled = false;
+    $("prompt").disabled = false;
+    $("send").disabled = false;
+    sessionStorage.setItem("restoai.key", state.key);
+    setStatus(`Connected, ${models.data.length} personas available.`);
+  }
+
+  async function complete(params, view) {
+    const completion = await (await api("/chat/completions", params)).json();
+    const reply = completion.choices[0].message.content || "";
+    setContent(view, "assistant", reply);
+    if (completion.usage) {
+      showUsage(completion.usage, false);
+    }
+    return reply;
+  }
+
+  async function stream(params, view) {
+    const response = await api("/chat/completions", { ...params, stream: true });
+    const reader = response.body.getReader();
+    const decoder = new TextDecoder();
+    let buffer = "";
+    let reply = "";
+    let usage = null;
+    for (;;) {
+      const { value, done } = await reader.read();
+      if (done) {
+        break;
+      }
+      buffer += decoder.decode(value, { stream: true });
+      const lines = buffer.split("\n");
+      buffer = lines.pop();
+      for (const line of lines) {
+        if (!line.startsWith("data:")) {
+          continue;
+        }
+        const data = line.slice(5).trim();
+        if (data === "[DONE]") {
+          continue;
+        }
+        const chunk = JSON.parse(data);
+        if (chunk.error) {
+          throw new Error(chunk.error.message);
+        }
+        const delta = chunk.choices && chunk.choices[0] && chunk.choices[0].delta;
+        if (delta && delta.content) {
+          reply += delta.content;
+          setContent(view, "assistant", reply);
+        }
+        usage = chunk.usage || usage;
+      }
+    }
+    if (usage) {
+      showUsage(usage, false);
+    } else {
+      await estimateUsage(params, reply);
+    }
+    return reply;
+  }
+
+  // Streamed answers carry no usage, count the tokens of the prompt with
+  // and without the answer instead.
+  async function estimateUsage(params, reply) {
+    const count = async (messages) =>
+      (await (await api("/chat/completions/count_tokens", { model: params.model, messages })).json())
+        .prompt_tokens;
+    try {
+      const prompt = await count(params.messages);
+      const total = await count([...params.messages, { role: "assistant", content: reply }]);
+      showUsage({ prompt_tokens: prompt, completion_tokens: total - prompt, total_tokens: total }, true);
+    } catch (_) {
+      // usage is informative only
+    }
+  }
+
+  async function send() {
+    const content = $("prompt").value.trim();
+    if (!content || state.busy) {
+      return;
+    }
+    const params = {
+      model: $("persona").value,
+      messages: [...state.messages, { role: "user", content }],
+      temperature: Number($("temperature").value),
+    };
+    const maxTokens = parseInt($("max-tokens").value, 10);
+    if (maxTokens > 0) {
+      params.max_tokens = maxTokens;
+    }
+
+    const question = addMessage("user", content);
+    const view = addMessage("assistant", "");
+    $("prompt").value = "";
+    setBusy(true);
+    setStatus("");
+    try {
+      const reply = $("stream").checked ? await stream(params, view) : await complete(params, view);
+      state.messages = [...params.messages, { role: "assistant", content: reply }];
+    } catch (e) {
+      // the question is not part of the conversation, leave it to retry
+      question.remove();
+      view.remove();
+      $("prompt").value = content;
+      setStatus(e.message, true);
+    } finally {
+      setBusy(false);
+    }
+  }
+
+  // -- wiring -----------------------------------------------------------
+
+  function init() {
+    $("api-key").value = state.key;
+
+    $("connect").addEventListener("submit", (event) => {
+      event.preventDefault();
+      state.key = $("api-key").value.trim();
+      connect().catch((e) => setStatus(e.message, true));
+    });
+
+    $("chat").addEventListener("submit", (event) => {
+      event.preventDefault();
+      send();
+    });
+
+    $("prompt").addEventListener("keydown", (event) => {
+      if (event.key === "Enter" && (event.ctrlKey || event.metaKey)) {
+        event.preventDefault();
+        send();
+      }
+    });
+
+    $("temperature").addEventListener("input", () => {
+      $("temperature-value").textContent = Number($("temperature").value).toFixed(1);
+    });
+
+    $("clear").addEventListener("click", () => {
+      state.messages = [];
+      $("messages").replaceChildren();
+      resetUsage();
+      setStatus("");
+    });
+
+    if (state.key) {
+      connect().catch((e) => setStatus(e.message, true));
+    }
+  }
+
+  init();
+})();